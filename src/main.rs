@@ -6,10 +6,22 @@
 //!     above mentioned Cargo.toml files.
 //!   - patch each Cargo.toml file to point to the git version
 //!
+//! Progress of `patch` is written to a state file after every step, so a run
+//! that failed halfway through can be continued with `patch --resume`.
+//! With a `[tracking_issue]` in the config, `patch --execute` also opens an
+//! issue listing every PR, and links each PR back to it.
+//!
+//! Every command can also write a JSON report of what it did in each repo,
+//! with `--report <path>`, or print it to stdout with `--format json`.
+//!
 //! You can also run `cleanup` to remove the local and remote branches that were
-//! created, run `update` to ensure each repo has generated a new lock file that
-//! points to the correct versions of the dependencies, and `reset` to run
-//! `cargo reset --hard` for each repo.
+//! created, `refresh` to rebase them onto the latest base branch and move the
+//! patched crates to their newest commits, run `update` to ensure each repo has
+//! generated a new lock file that points to the correct versions of the
+//! dependencies, `unpatch` to remove the git patches again once the crates are
+//! released, `reset` to run `cargo reset --hard` for each repo, `status` to see
+//! where each repo is in the release, `ci` to wait for the checks on the PRs,
+//! and `merge` to merge them in dependency order.
 //!
 //! This is mostly powered through the config file. You can set a list of
 //! the directories that point to the repos you want updated (absolute paths),
@@ -19,25 +31,442 @@
 //!
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
-use log::{error, info};
-use serde::Deserialize;
-use std::collections::HashSet;
+use clap::{Parser, Subcommand, ValueEnum};
+use log::{debug, error, info, warn, Level, Metadata, Record};
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use similar::TextDiff;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::io::Write;
-use std::path::{Path, PathBuf};
-use std::process::Command as Cmd;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command as Cmd, ExitCode, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table, TableLike};
 
 #[derive(Deserialize)]
 struct Config {
     /// List of directories that need to be patched.
     ///
-    /// Should be the absolute path to the directory.
-    directories: Vec<PathBuf>,
+    /// Each entry is either the absolute path to the directory, or a table
+    /// with the path and per-directory overrides.
+    #[serde(deserialize_with = "deserialize_directories")]
+    directories: Vec<Directory>,
     /// List of crates to patch and their githubs.
     crates: Vec<Crate>,
     /// Name of the branch.
     branch_name: String,
+    /// How PRs are opened, for every directory that doesn't override it.
+    #[serde(default)]
+    pull_request: PullRequestConfig,
+    /// How commits are made, for every directory that doesn't override it.
+    #[serde(default)]
+    commit: CommitConfig,
+    /// Issue that `patch --execute` opens or updates with a checklist of the
+    /// PRs, if set.
+    tracking_issue: Option<TrackingIssueConfig>,
+}
+
+/// The tracking issue for a release, under `[tracking_issue]` in the config.
+#[derive(Debug, Deserialize)]
+struct TrackingIssueConfig {
+    /// GitHub repo to open the issue in, as `owner/name`.
+    repo: String,
+    /// Title of the issue, which can use `{branch}`. An open issue with the
+    /// same title is updated instead of opening a new one.
+    title: Option<String>,
+    /// Labels to add to the issue when it is opened.
+    #[serde(default)]
+    labels: Vec<String>,
+}
+
+/// A repo that needs to be patched.
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct Directory {
+    /// Absolute path to the repo.
+    path: PathBuf,
+    /// Branch that new branches are created from and PRs are opened against.
+    ///
+    /// Detected from `<remote>/HEAD` when not set.
+    base_branch: Option<String>,
+    /// Name of the git remote to pull from and push to. Defaults to `origin`.
+    remote: Option<String>,
+    /// Leave `deny.toml` alone in this repo, even if it exists.
+    #[serde(default)]
+    skip_deny: bool,
+    /// Extra commands to run after `cargo check` in `update`, run through
+    /// `sh -c` in the repo.
+    #[serde(default)]
+    check_commands: Vec<String>,
+    /// Only patch these crates in this repo, if set.
+    include_crates: Option<Vec<String>>,
+    /// Never patch these crates in this repo.
+    #[serde(default)]
+    exclude_crates: Vec<String>,
+    /// Labels to add to PRs created in this repo, on top of
+    /// `pull_request.labels`.
+    #[serde(default)]
+    pr_labels: Vec<String>,
+    /// Overrides for how PRs are opened in this repo. Settings that are not
+    /// set here come from the top level `[pull_request]`.
+    #[serde(default)]
+    pull_request: PullRequestConfig,
+    /// Overrides for how commits are made in this repo. Settings that are not
+    /// set here come from the top level `[commit]`.
+    #[serde(default)]
+    commit: CommitConfig,
+    /// How `merge` merges the PR in this repo, overriding `--method`.
+    merge_method: Option<MergeMethod>,
+}
+
+impl Directory {
+    fn remote(&self) -> &str {
+        self.remote.as_deref().unwrap_or("origin")
+    }
+
+    /// The configured crates, narrowed down by `include_crates` and
+    /// `exclude_crates`.
+    fn crates(&self, crates: &[Crate]) -> Vec<Crate> {
+        crates
+            .iter()
+            .filter(|c| {
+                self.include_crates
+                    .as_ref()
+                    .is_none_or(|include| include.contains(&c.name))
+                    && !self.exclude_crates.contains(&c.name)
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn patch_values<'a>(
+        &'a self,
+        branch_name: &'a str,
+        patched_crates: &[PatchedCrate],
+    ) -> TemplateValues<'a> {
+        TemplateValues {
+            branch: branch_name,
+            crates: crate_list(patched_crates),
+            repo: &self.path,
+            version: None,
+        }
+    }
+
+    fn unpatch_values<'a>(
+        &'a self,
+        branch_name: &'a str,
+        removed_crates: &[Crate],
+        version: Option<&'a str>,
+    ) -> TemplateValues<'a> {
+        TemplateValues {
+            branch: branch_name,
+            crates: removed_crates
+                .iter()
+                .map(|c| format!("- `{}`", c.name))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            repo: &self.path,
+            version,
+        }
+    }
+
+    /// The commit message `patch` uses for `patched_crates`.
+    fn patch_commit_message(&self, branch_name: &str, patched_crates: &[PatchedCrate]) -> String {
+        render_commit_message(
+            self.commit.subject.as_deref(),
+            self.commit.body.as_deref(),
+            &self.patch_values(branch_name, patched_crates),
+            "chore: add git patches for dependencies",
+            patch_commit_body(patched_crates),
+        )
+    }
+
+    /// The commit message `unpatch` uses for `removed_crates`, using
+    /// `default_body` when no body template is configured.
+    fn unpatch_commit_message(
+        &self,
+        branch_name: &str,
+        removed_crates: &[Crate],
+        version: Option<&str>,
+        default_body: String,
+    ) -> String {
+        render_commit_message(
+            self.commit.unpatch_subject.as_deref(),
+            self.commit.unpatch_body.as_deref(),
+            &self.unpatch_values(branch_name, removed_crates, version),
+            "chore: remove git patches",
+            default_body,
+        )
+    }
+
+    /// The commit message `refresh` uses when it updates `patched_crates`.
+    fn refresh_commit_message(&self, branch_name: &str, patched_crates: &[PatchedCrate]) -> String {
+        render_commit_message(
+            self.commit.refresh_subject.as_deref(),
+            self.commit.refresh_body.as_deref(),
+            &self.patch_values(branch_name, patched_crates),
+            "chore: update patched dependencies",
+            format!(
+                "Updates the following dependencies to their latest git versions:\n\n{}",
+                crate_list(patched_crates)
+            ),
+        )
+    }
+
+    /// The PR that `patch` opens for `patched_crates`.
+    fn patch_pull_request(
+        &self,
+        branch_name: &str,
+        patched_crates: &[PatchedCrate],
+    ) -> PullRequest {
+        self.render_pull_request(
+            self.pull_request.title.as_deref(),
+            self.pull_request.body.as_deref(),
+            &self.patch_values(branch_name, patched_crates),
+            patch_pr_body(patched_crates),
+        )
+    }
+
+    /// The PR that `unpatch` opens for `removed_crates`, using `default_body`
+    /// when no body template is configured.
+    fn unpatch_pull_request(
+        &self,
+        branch_name: &str,
+        removed_crates: &[Crate],
+        version: Option<&str>,
+        default_body: String,
+    ) -> PullRequest {
+        self.render_pull_request(
+            self.pull_request.unpatch_title.as_deref(),
+            self.pull_request.unpatch_body.as_deref(),
+            &self.unpatch_values(branch_name, removed_crates, version),
+            default_body,
+        )
+    }
+
+    fn render_pull_request(
+        &self,
+        title: Option<&str>,
+        body: Option<&str>,
+        values: &TemplateValues,
+        default_body: String,
+    ) -> PullRequest {
+        let config = &self.pull_request;
+        let mut labels = config.labels.clone().unwrap_or_default();
+        for label in &self.pr_labels {
+            if !labels.contains(label) {
+                labels.push(label.clone());
+            }
+        }
+        PullRequest {
+            title: title.map_or_else(
+                || "chore: release prep".to_string(),
+                |title| values.render(title),
+            ),
+            body: body.map_or(default_body, |body| values.render(body)),
+            labels,
+            reviewers: config.reviewers.clone().unwrap_or_default(),
+            assignees: config.assignees.clone().unwrap_or_default(),
+            milestone: config.milestone.clone(),
+            draft: config.draft.unwrap_or(false),
+        }
+    }
+}
+
+/// How PRs are opened, under `[pull_request]` in the config or per directory.
+///
+/// `title` and `body` are used by `patch`, `unpatch_title` and `unpatch_body`
+/// by `unpatch`. They are templates that can use `{branch}`, `{crates}` (the
+/// list of crates and their git refs), `{repo}` (the directory name) and
+/// `{version}` (the version passed to `unpatch --version`).
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct PullRequestConfig {
+    title: Option<String>,
+    body: Option<String>,
+    unpatch_title: Option<String>,
+    unpatch_body: Option<String>,
+    labels: Option<Vec<String>>,
+    /// Users or teams to request a review from.
+    reviewers: Option<Vec<String>>,
+    assignees: Option<Vec<String>>,
+    milestone: Option<String>,
+    /// Open the PRs as drafts.
+    draft: Option<bool>,
+}
+
+impl PullRequestConfig {
+    /// Fills in the settings that are not set in `self` from `defaults`.
+    fn or(self, defaults: &PullRequestConfig) -> PullRequestConfig {
+        PullRequestConfig {
+            title: self.title.or_else(|| defaults.title.clone()),
+            body: self.body.or_else(|| defaults.body.clone()),
+            unpatch_title: self
+                .unpatch_title
+                .or_else(|| defaults.unpatch_title.clone()),
+            unpatch_body: self.unpatch_body.or_else(|| defaults.unpatch_body.clone()),
+            labels: self.labels.or_else(|| defaults.labels.clone()),
+            reviewers: self.reviewers.or_else(|| defaults.reviewers.clone()),
+            assignees: self.assignees.or_else(|| defaults.assignees.clone()),
+            milestone: self.milestone.or_else(|| defaults.milestone.clone()),
+            draft: self.draft.or(defaults.draft),
+        }
+    }
+}
+
+/// How commits are made, under `[commit]` in the config or per directory.
+///
+/// `subject` and `body` are used by `patch`, `unpatch_subject` and
+/// `unpatch_body` by `unpatch`, and `refresh_subject` and `refresh_body` by
+/// `refresh`. They are templates with the same placeholders as the PR
+/// templates.
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct CommitConfig {
+    subject: Option<String>,
+    body: Option<String>,
+    unpatch_subject: Option<String>,
+    unpatch_body: Option<String>,
+    refresh_subject: Option<String>,
+    refresh_body: Option<String>,
+    /// Add a `Signed-off-by` trailer, for repos that require a DCO.
+    signoff: Option<bool>,
+    /// Sign the commits, with the key and format from the git config unless
+    /// `signing_key` and `signing_format` are set.
+    sign: Option<bool>,
+    /// Key to sign the commits with. Setting it turns on `sign`.
+    signing_key: Option<String>,
+    /// `gpg`, `ssh` or `x509`, overriding git's `gpg.format`.
+    signing_format: Option<String>,
+    /// Author of the commits, as `Name <email>`.
+    author: Option<String>,
+}
+
+impl CommitConfig {
+    /// Fills in the settings that are not set in `self` from `defaults`.
+    fn or(self, defaults: &CommitConfig) -> CommitConfig {
+        CommitConfig {
+            subject: self.subject.or_else(|| defaults.subject.clone()),
+            body: self.body.or_else(|| defaults.body.clone()),
+            unpatch_subject: self
+                .unpatch_subject
+                .or_else(|| defaults.unpatch_subject.clone()),
+            unpatch_body: self.unpatch_body.or_else(|| defaults.unpatch_body.clone()),
+            refresh_subject: self
+                .refresh_subject
+                .or_else(|| defaults.refresh_subject.clone()),
+            refresh_body: self.refresh_body.or_else(|| defaults.refresh_body.clone()),
+            signoff: self.signoff.or(defaults.signoff),
+            sign: self.sign.or(defaults.sign),
+            signing_key: self.signing_key.or_else(|| defaults.signing_key.clone()),
+            signing_format: self
+                .signing_format
+                .or_else(|| defaults.signing_format.clone()),
+            author: self.author.or_else(|| defaults.author.clone()),
+        }
+    }
+}
+
+/// A PR to open, with its templates filled in.
+#[derive(Debug)]
+struct PullRequest {
+    title: String,
+    body: String,
+    labels: Vec<String>,
+    reviewers: Vec<String>,
+    assignees: Vec<String>,
+    milestone: Option<String>,
+    draft: bool,
+}
+
+/// The values of the placeholders in PR templates.
+struct TemplateValues<'a> {
+    branch: &'a str,
+    crates: String,
+    repo: &'a Path,
+    version: Option<&'a str>,
+}
+
+/// Fills in the commit message templates, falling back to `default_subject`
+/// and `default_body` for the ones that are not set.
+fn render_commit_message(
+    subject: Option<&str>,
+    body: Option<&str>,
+    values: &TemplateValues,
+    default_subject: &str,
+    default_body: String,
+) -> String {
+    let subject = subject.map_or_else(|| default_subject.to_string(), |s| values.render(s));
+    let body = body.map_or(default_body, |body| values.render(body));
+    format!("{subject}\n\n{body}")
+}
+
+impl TemplateValues<'_> {
+    fn render(&self, template: &str) -> String {
+        let repo = self.repo.file_name().expect("checked").to_string_lossy();
+        template
+            .replace("{branch}", self.branch)
+            .replace("{crates}", &self.crates)
+            .replace("{repo}", &repo)
+            .replace("{version}", self.version.unwrap_or_default())
+    }
+}
+
+/// An entry in `directories`, either a bare path or a table with overrides.
+enum DirectoryEntry {
+    Path(PathBuf),
+    Table(Box<Directory>),
+}
+
+// Not `#[serde(untagged)]`, since that hides why a table failed to parse
+// behind "data did not match any variant".
+impl<'de> Deserialize<'de> for DirectoryEntry {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = DirectoryEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a path or a table with a `path` key")
+            }
+
+            fn visit_str<E: de::Error>(self, path: &str) -> Result<Self::Value, E> {
+                Ok(DirectoryEntry::Path(PathBuf::from(path)))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let directory = Directory::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(DirectoryEntry::Table(Box::new(directory)))
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
+
+fn deserialize_directories<'de, D>(deserializer: D) -> Result<Vec<Directory>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = Vec::<DirectoryEntry>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| match entry {
+            DirectoryEntry::Path(path) => Directory {
+                path,
+                ..Default::default()
+            },
+            DirectoryEntry::Table(directory) => *directory,
+        })
+        .collect())
 }
 
 #[derive(Debug, Deserialize, Clone)] // Add `Clone` here
@@ -46,10 +475,218 @@ struct Crate {
     name: String,
     /// URL of the repo
     repo_url: String,
+    /// Branch the patch should track.
+    ///
+    /// When none of `branch`, `rev` or `tag` are set, the patch tracks
+    /// `branch_name`.
+    branch: Option<String>,
+    /// Specific commit the patch should point to.
+    rev: Option<String>,
+    /// Tag the patch should point to.
+    tag: Option<String>,
+}
+
+impl Crate {
+    /// The git ref this crate should be patched to, falling back to
+    /// `branch_name` when the crate does not set one itself.
+    fn git_ref(&self, branch_name: &str) -> GitRef {
+        if let Some(rev) = &self.rev {
+            GitRef::Rev(rev.clone())
+        } else if let Some(tag) = &self.tag {
+            GitRef::Tag(tag.clone())
+        } else if let Some(branch) = &self.branch {
+            GitRef::Branch(branch.clone())
+        } else {
+            GitRef::Branch(branch_name.to_string())
+        }
+    }
+}
+
+/// The git ref a `[patch.crates-io]` entry points to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum GitRef {
+    Branch(String),
+    Rev(String),
+    Tag(String),
+}
+
+impl GitRef {
+    /// The key used for this ref in a cargo git dependency.
+    fn key(&self) -> &'static str {
+        match self {
+            GitRef::Branch(_) => "branch",
+            GitRef::Rev(_) => "rev",
+            GitRef::Tag(_) => "tag",
+        }
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            GitRef::Branch(v) | GitRef::Rev(v) | GitRef::Tag(v) => v,
+        }
+    }
+
+    /// Read the ref out of a cargo git dependency table, if it has one.
+    fn from_dependency(dep: &toml::Value) -> Option<Self> {
+        if let Some(rev) = dep.get("rev").and_then(|v| v.as_str()) {
+            Some(GitRef::Rev(rev.to_string()))
+        } else if let Some(tag) = dep.get("tag").and_then(|v| v.as_str()) {
+            Some(GitRef::Tag(tag.to_string()))
+        } else {
+            dep.get("branch")
+                .and_then(|v| v.as_str())
+                .map(|branch| GitRef::Branch(branch.to_string()))
+        }
+    }
+}
+
+/// A configured crate that has a patch in `[patch.crates-io]`.
+#[derive(Debug, Clone)]
+struct PatchedCrate {
+    krate: Crate,
+    /// The git ref the patch points to, `None` when the entry does not name
+    /// one, like a `git` patch on the default branch or a `path` patch.
+    git_ref: Option<GitRef>,
+    /// Whether the crate is only used transitively, through another
+    /// dependency, rather than referenced in a manifest.
+    transitive: bool,
+}
+
+impl fmt::Display for PatchedCrate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` from `{}`", self.krate.name, self.krate.repo_url)?;
+        match (&self.git_ref, self.transitive) {
+            (Some(git_ref), false) => write!(f, " ({git_ref})"),
+            (Some(git_ref), true) => write!(f, " ({git_ref}, used transitively)"),
+            (None, true) => write!(f, " (used transitively)"),
+            (None, false) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for GitRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} `{}`", self.key(), self.value())
+    }
+}
+
+/// The results of a command, written with `--report` or `--format json`.
+#[derive(Debug, Serialize)]
+struct Report {
+    /// The subcommand that ran, like `patch`.
+    command: &'static str,
+    repos: Vec<RepoReport>,
+    /// URL of the tracking issue that lists the PRs.
+    tracking_issue: Option<String>,
+}
+
+/// The result of a command in a single repo.
+#[derive(Debug, Default, Serialize)]
+struct RepoReport {
+    path: PathBuf,
+    /// The branch the command worked on.
+    branch: Option<String>,
+    /// The crates that were patched, or unpatched.
+    crates: Vec<CrateReport>,
+    /// The commit at the tip of `branch` when the command finished.
+    commit: Option<String>,
+    /// URL of the PR that was created, or closed by `cleanup`.
+    pr_url: Option<String>,
+    /// Result of `cargo check` and the check commands.
+    check: Option<CheckReport>,
+    /// The release state of the repo, from `status`.
+    status: Option<RepoStatus>,
+    /// The checks of the PR, from `ci`.
+    ci: Option<CiReport>,
+    /// The error the command failed with.
+    error: Option<String>,
+    /// Whether the repo was not processed because another repo failed first.
+    skipped: bool,
+}
+
+impl RepoReport {
+    fn new(directory: &Directory) -> Self {
+        Self {
+            path: directory.path.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn failed(directory: &Directory, error: &anyhow::Error) -> Self {
+        Self {
+            error: Some(format!("{error:#}")),
+            ..Self::new(directory)
+        }
+    }
+
+    fn skipped(directory: &Directory) -> Self {
+        Self {
+            skipped: true,
+            ..Self::new(directory)
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CrateReport {
+    name: String,
+    repo_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git_ref: Option<GitRef>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transitive: Option<bool>,
+}
+
+impl From<&PatchedCrate> for CrateReport {
+    fn from(patched: &PatchedCrate) -> Self {
+        Self {
+            git_ref: patched.git_ref.clone(),
+            transitive: Some(patched.transitive),
+            ..Self::from(&patched.krate)
+        }
+    }
+}
+
+impl From<&Crate> for CrateReport {
+    fn from(krate: &Crate) -> Self {
+        Self {
+            name: krate.name.clone(),
+            repo_url: krate.repo_url.clone(),
+            git_ref: None,
+            transitive: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CheckReport {
+    success: bool,
+    /// Warnings and errors reported by the checks.
+    diagnostics: Vec<String>,
+}
+
+/// What `patch` or `unpatch` did in a single repo.
+struct BranchOutcome<C> {
+    /// The crates that were changed.
+    crates: Vec<C>,
+    /// The commit at the tip of the branch.
+    commit: Option<String>,
+    /// URL of the created PR.
+    pr_url: Option<String>,
 }
 
+/// Exit code when the config file cannot be read or is invalid.
+const EXIT_CONFIG_ERROR: u8 = 3;
+/// Exit code when the command failed, or was skipped, in at least one repo.
+const EXIT_REPO_FAILURE: u8 = 4;
+
 #[derive(Parser)]
-#[command(name = "patch-iroh-main")]
+#[command(
+    name = "patch-iroh-main",
+    after_help = "Exits with 1 on unexpected errors, 2 on invalid arguments, 3 when the config \
+                  is invalid and 4 when any repo failed."
+)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -59,6 +696,51 @@ struct Cli {
 
     #[arg(long, short, help = "Enable verbose logging")]
     verbose: bool,
+
+    #[arg(
+        long,
+        short,
+        default_value_t = 1,
+        help = "Number of repos to process at the same time"
+    )]
+    jobs: usize,
+
+    #[arg(
+        long,
+        help = "Write a JSON report of the results of each repo to this path"
+    )]
+    report: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = Format::Text, help = "Output format of the results")]
+    format: Format,
+
+    #[arg(
+        long,
+        conflicts_with = "keep_going",
+        help = "Stop processing repos after the first one that fails"
+    )]
+    fail_fast: bool,
+
+    #[arg(
+        long,
+        help = "Keep processing the remaining repos when one fails (the default)"
+    )]
+    keep_going: bool,
+
+    #[arg(
+        long,
+        help = "Stash uncommitted changes before switching branches and restore them afterwards, \
+                instead of skipping repos that have them"
+    )]
+    stash: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Log the results
+    Text,
+    /// Print a JSON report of the results to stdout
+    Json,
 }
 
 #[derive(Subcommand)]
@@ -71,557 +753,4054 @@ enum Commands {
         /// Whether to execute the full process (push and create PR).
         #[arg(long, default_value_t = false)]
         execute: bool,
+        /// Only patch crates that are direct dependencies, ignoring crates
+        /// that are only pulled in transitively through Cargo.lock.
+        #[arg(long, default_value_t = false)]
+        direct_only: bool,
+        /// Continue a previous run from the last step that finished in each
+        /// repo, as recorded in the state file.
+        #[arg(long, default_value_t = false)]
+        resume: bool,
+        /// Path to the state file. Defaults to the config path with a
+        /// `.state.toml` extension.
+        #[arg(long)]
+        state: Option<PathBuf>,
+        /// Print the changes and commands that would run, without changing
+        /// anything.
+        #[arg(long, default_value_t = false, conflicts_with = "resume")]
+        plan: bool,
+    },
+    /// Create a new branch for each repo that removes the configured crates
+    /// from `[patch.crates-io]` and `deny.toml`, for after a release.
+    ///
+    /// When `execute` is true, will also push the branches and creates PRs for each.
+    Unpatch {
+        /// Whether to execute the full process (push and create PR).
+        #[arg(long, default_value_t = false)]
+        execute: bool,
+        /// Released version to set the unpatched dependencies to.
+        #[arg(long)]
+        version: Option<String>,
+        /// Name of the branch to create. Defaults to `unpatch-<branch_name>`.
+        #[arg(long)]
+        branch: Option<String>,
+        /// Print the changes and commands that would run, without changing
+        /// anything.
+        #[arg(long, default_value_t = false)]
+        plan: bool,
+    },
+    /// Cleanup the created branches, deleting them locally and remotely and
+    /// closing any open PR for them
+    Cleanup {
+        /// Name of the branch to delete. Defaults to `branch_name`.
+        #[arg(long)]
+        branch: Option<String>,
+        /// Comment to leave on the PRs when closing them.
+        #[arg(long)]
+        comment: Option<String>,
+    },
+    /// Rebase the existing patch branches onto the latest base branch and
+    /// run `cargo update` for the patched crates, to pick up new upstream
+    /// commits.
+    ///
+    /// When `execute` is true, will also force-push the branches (with lease).
+    Refresh {
+        /// Whether to push the refreshed branches.
+        #[arg(long, default_value_t = false)]
+        execute: bool,
+        /// Merge the base branch into the patch branch instead of rebasing.
+        #[arg(long, default_value_t = false)]
+        merge: bool,
+        /// Name of the patch branch. Defaults to `branch_name`.
+        #[arg(long)]
+        branch: Option<String>,
     },
-    /// Cleanup the created branches, deleting them locally and remotely
-    Cleanup,
     /// Run `cargo update` (updating only the dependencies listed), and
     /// `cargo check` on each repo
     Update,
     /// run `git reset --hard` on each repo
-    Reset,
+    Reset {
+        /// Reset repos even when it discards uncommitted changes.
+        #[arg(long, default_value_t = false)]
+        force: bool,
+    },
+    /// Show the release state of each repo: its branches, patches, deny.toml
+    /// and PR
+    Status {
+        /// Name of the patch branch. Defaults to `branch_name`.
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    /// Wait for the checks on the open PR of each repo to finish, and report
+    /// the ones that failed
+    Ci {
+        /// Name of the patch branch. Defaults to `branch_name`.
+        #[arg(long)]
+        branch: Option<String>,
+        /// How long to wait for the checks to finish, in minutes.
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+        /// How often to poll the checks, in seconds.
+        #[arg(long, default_value_t = 30)]
+        interval: u64,
+    },
+    /// Merge the approved and green PRs, ordered so that each repo is merged
+    /// after the repos it depends on, stopping at the first failure.
+    ///
+    /// When `execute` is false, only checks that the PRs are ready to merge.
+    Merge {
+        /// Whether to merge the PRs.
+        #[arg(long, default_value_t = false)]
+        execute: bool,
+        /// Name of the patch branch. Defaults to `branch_name`.
+        #[arg(long)]
+        branch: Option<String>,
+        /// How to merge the PRs, for repos that don't set `merge_method`.
+        #[arg(long, value_enum, default_value_t = MergeMethod::Squash)]
+        method: MergeMethod,
+    },
+}
+
+impl Commands {
+    fn name(&self) -> &'static str {
+        match self {
+            Commands::Patch { .. } => "patch",
+            Commands::Unpatch { .. } => "unpatch",
+            Commands::Cleanup { .. } => "cleanup",
+            Commands::Refresh { .. } => "refresh",
+            Commands::Update => "update",
+            Commands::Reset { .. } => "reset",
+            Commands::Status { .. } => "status",
+            Commands::Ci { .. } => "ci",
+            Commands::Merge { .. } => "merge",
+        }
+    }
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
     // Initialize env_logger
-    env_logger::Builder::from_default_env()
+    let logger = env_logger::Builder::from_default_env()
         .filter_level(if cli.verbose {
             log::LevelFilter::Info
         } else {
             log::LevelFilter::Warn
         })
-        .init();
+        .build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(RepoLogger(logger)))?;
 
-    let config = load_config(&cli.config)?;
+    let config = match load_config(&cli.config) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{e:?}");
+            return Ok(ExitCode::from(EXIT_CONFIG_ERROR));
+        }
+    };
+    let options = RunOptions {
+        jobs: cli.jobs.max(1),
+        fail_fast: cli.fail_fast,
+        stash: cli.stash,
+    };
+    PRINT_TO_STDERR.store(cli.format == Format::Json, Ordering::Relaxed);
 
-    match cli.command {
-        Commands::Patch { execute } => patch_crates(
+    let command = cli.command.name();
+    let mut tracking_issue = None;
+    let mut tracking_issue_failed = false;
+    let repos = match cli.command {
+        Commands::Patch {
+            execute,
+            direct_only,
+            resume: _,
+            state: _,
+            plan: true,
+        } => plan_repos(&config.directories, &config.branch_name, options, |dir| {
+            plan_patch(
+                dir,
+                &config.branch_name,
+                &config.crates,
+                execute,
+                direct_only,
+            )
+        })?,
+        Commands::Patch {
+            execute,
+            direct_only,
+            resume,
+            state,
+            plan: false,
+        } => {
+            let state_path = state.unwrap_or_else(|| cli.config.with_extension("state.toml"));
+            let state = if resume {
+                match StateFile::load(state_path, &config.branch_name) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        error!("{e:?}");
+                        return Ok(ExitCode::from(EXIT_CONFIG_ERROR));
+                    }
+                }
+            } else {
+                StateFile::new(state_path, &config.branch_name)?
+            };
+            let repos = patch_crates(
+                &config.directories,
+                &config.branch_name,
+                &config.crates,
+                execute,
+                direct_only,
+                &state,
+                options,
+            )?;
+            if let Some(issue) = config.tracking_issue.as_ref().filter(|_| execute) {
+                match update_tracking_issue(issue, &config.branch_name, &repos) {
+                    Ok(url) => tracking_issue = url,
+                    Err(e) => {
+                        error!("{e:?}");
+                        tracking_issue_failed = true;
+                    }
+                }
+            }
+            repos
+        }
+        Commands::Unpatch {
+            execute,
+            version,
+            branch,
+            plan,
+        } => {
+            let branch_name = branch.unwrap_or_else(|| format!("unpatch-{}", config.branch_name));
+            if plan {
+                plan_repos(&config.directories, &branch_name, options, |dir| {
+                    plan_unpatch(
+                        dir,
+                        &branch_name,
+                        &config.crates,
+                        version.as_deref(),
+                        execute,
+                    )
+                })?
+            } else {
+                unpatch_crates(
+                    &config.directories,
+                    &branch_name,
+                    &config.crates,
+                    version.as_deref(),
+                    execute,
+                    options,
+                )?
+            }
+        }
+        Commands::Cleanup { branch, comment } => cleanup_branches(
+            &config.directories,
+            branch.as_deref().unwrap_or(&config.branch_name),
+            comment.as_deref(),
+            options,
+        )?,
+        Commands::Refresh {
+            execute,
+            merge,
+            branch,
+        } => refresh_branches(
+            &config.directories,
+            branch.as_deref().unwrap_or(&config.branch_name),
+            &config.crates,
+            execute,
+            merge,
+            options,
+        )?,
+        Commands::Update => update_and_check(&config.directories, &config.crates, options)?,
+        Commands::Reset { force } => reset(&config.directories, force, options)?,
+        Commands::Status { branch } => status(
+            &config.directories,
+            branch.as_deref().unwrap_or(&config.branch_name),
+            &config.crates,
+            options,
+        )?,
+        Commands::Ci {
+            branch,
+            timeout,
+            interval,
+        } => wait_for_ci(
             &config.directories,
-            &config.branch_name,
+            branch.as_deref().unwrap_or(&config.branch_name),
+            Duration::from_secs(timeout * 60),
+            Duration::from_secs(interval),
+            options,
+        )?,
+        Commands::Merge {
+            execute,
+            branch,
+            method,
+        } => merge_pull_requests(
+            &config.directories,
+            branch.as_deref().unwrap_or(&config.branch_name),
             &config.crates,
+            method,
             execute,
+            options,
         )?,
-        Commands::Cleanup => cleanup_branches(&config.directories)?,
-        Commands::Update => update_and_check(&config.directories, &config.crates)?,
-        Commands::Reset => reset(&config.directories)?,
+    };
+
+    let report = Report {
+        command,
+        repos,
+        tracking_issue,
+    };
+    if let Some(path) = &cli.report {
+        let json =
+            serde_json::to_string_pretty(&report).with_context(|| "Failed to serialize report")?;
+        fs::write(path, json)
+            .with_context(|| format!("Failed to write report to {}", path.display()))?;
+    }
+    if cli.format == Format::Json {
+        let json =
+            serde_json::to_string_pretty(&report).with_context(|| "Failed to serialize report")?;
+        println!("{json}");
     }
 
-    Ok(())
+    if tracking_issue_failed || report.repos.iter().any(|r| r.error.is_some() || r.skipped) {
+        return Ok(ExitCode::from(EXIT_REPO_FAILURE));
+    }
+    Ok(ExitCode::SUCCESS)
 }
 
 fn load_config(path: &PathBuf) -> Result<Config> {
     let config_content = fs::read_to_string(path)
         .with_context(|| format!("Failed to read config file at {}", path.display()))?;
-    let config: Config =
+    let mut config: Config =
         toml::from_str(&config_content).with_context(|| "Failed to parse config file")?;
 
     // Validate that all directories are absolute paths
     for dir in &config.directories {
-        if !dir.is_absolute() {
+        if !dir.path.is_absolute() {
             return Err(anyhow::anyhow!(
                 "Directory path '{}' is not absolute",
-                dir.display()
+                dir.path.display()
             ));
         }
     }
 
+    // Validate that each crate points to at most one git ref
+    for krate in &config.crates {
+        let refs = [&krate.branch, &krate.rev, &krate.tag]
+            .iter()
+            .filter(|r| r.is_some())
+            .count();
+        if refs > 1 {
+            bail!(
+                "Crate '{}' can only set one of `branch`, `rev` or `tag`",
+                krate.name
+            );
+        }
+    }
+
+    for dir in &mut config.directories {
+        dir.pull_request = std::mem::take(&mut dir.pull_request).or(&config.pull_request);
+        dir.commit = std::mem::take(&mut dir.commit).or(&config.commit);
+    }
+
     Ok(config)
 }
 
-fn patch_crates(
-    directories: &[PathBuf],
-    branch_name: &str,
-    crates: &[Crate],
-    execute: bool,
-) -> Result<()> {
-    // info!("Patching crates...");
-    let mut successful = vec![];
-    let mut unsuccessful = vec![];
-    for dir in directories {
-        match patch_crate(dir, branch_name, crates, execute) {
-            Err(e) => {
-                error!("{e:?}");
-                unsuccessful.push(dir);
-            }
-            Ok(()) => {
-                successful.push(dir);
-            }
-        }
+/// A line of output from a repo that is processed on a worker thread.
+enum CapturedLine {
+    Log {
+        level: Level,
+        target: String,
+        message: String,
+    },
+    Print(String),
+}
+
+thread_local! {
+    /// The output captured for the repo this thread is working on, if any.
+    static CAPTURED: RefCell<Option<Vec<CapturedLine>>> = const { RefCell::new(None) };
+}
+
+/// Wraps env_logger so that the output of a repo being processed on a worker
+/// thread can be captured, and printed in one piece once the repo is done.
+struct RepoLogger(env_logger::Logger);
+
+impl log::Log for RepoLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
     }
-    if !successful.is_empty() {
-        info!("crates successfully patched:");
-        for cr in successful {
-            let filename = cr.file_name().unwrap().to_string_lossy();
-            info!("\t{filename}");
+
+    fn log(&self, record: &Record) {
+        if !self.0.matches(record) {
+            return;
+        }
+        let captured = CAPTURED.with_borrow_mut(|captured| match captured {
+            Some(lines) => {
+                lines.push(CapturedLine::Log {
+                    level: record.level(),
+                    target: record.target().to_string(),
+                    message: record.args().to_string(),
+                });
+                true
+            }
+            None => false,
+        });
+        if !captured {
+            self.0.log(record);
         }
     }
 
-    if !unsuccessful.is_empty() {
-        info!("crates that could not be patched:");
-        for cr in unsuccessful {
-            let filename = cr.file_name().unwrap().to_string_lossy();
-            info!("\t{filename}");
-        }
+    fn flush(&self) {
+        self.0.flush();
     }
-    Ok(())
 }
 
-fn patch_crate(
-    directory: &PathBuf,
-    branch_name: &str,
-    crates: &[Crate],
-    execute: bool,
-) -> Result<()> {
-    std::env::set_current_dir(directory)?;
-    let dir_name = directory.file_name().expect("checked");
-    info!("Working with repo {dir_name:?}");
+/// Set with `--format json`, so that stdout only contains the report.
+static PRINT_TO_STDERR: AtomicBool = AtomicBool::new(false);
 
-    // Check if the branch already exists
-    let branch_exists = Cmd::new("git")
-        .args(["rev-parse", "--verify", branch_name])
-        .status()
-        .map(|status| status.success())
-        .unwrap_or(false);
+/// Prints a line to stdout, or adds it to the captured output of the repo
+/// this thread is working on.
+fn print_line(line: String) {
+    CAPTURED.with_borrow_mut(|captured| match captured {
+        Some(lines) => lines.push(CapturedLine::Print(line)),
+        None => write_line(&line),
+    });
+}
 
-    if !branch_exists {
-        create_and_checkout_branch(branch_name)?;
+fn write_line(line: &str) {
+    if PRINT_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{line}");
     } else {
-        info!(
-            "Branch '{}' already exists. Skipping branch creation.",
-            branch_name
-        );
+        println!("{line}");
     }
+}
 
-    // Ensure patches are in Cargo.toml and get the list of updated crates
-    let updated_crates = ensure_patches_in_cargo_toml(crates, branch_name)?;
-
-    // If there are updated crates, update deny.toml if it exists
-    if !updated_crates.is_empty() {
-        // Run `cargo update` to update dependencies
-        info!("Running `cargo update`...");
-        cargo_update(&updated_crates)?;
-
-        // Check if deny.toml exists and update it
-        update_deny_toml(&updated_crates)?;
+/// Runs `f`, capturing all of the log output and printed lines on this thread.
+fn capture_output<T>(f: impl FnOnce() -> T) -> (T, Vec<CapturedLine>) {
+    CAPTURED.set(Some(vec![]));
+    let result = f();
+    let lines = CAPTURED.take().unwrap_or_default();
+    (result, lines)
+}
 
-        // Commit changes
-        commit_changes(&updated_crates)?;
+fn print_captured(lines: Vec<CapturedLine>) {
+    for line in lines {
+        match line {
+            CapturedLine::Log {
+                level,
+                target,
+                message,
+            } => log::logger().log(
+                &Record::builder()
+                    .level(level)
+                    .target(&target)
+                    .args(format_args!("{message}"))
+                    .build(),
+            ),
+            CapturedLine::Print(line) => write_line(&line),
+        }
     }
+}
 
-    // Push and create PR if `execute` is true
-    if execute {
-        push_branch(branch_name)?;
-
-        // Get all crates in [patch.crates-io] that are in our list of crates
-        let cargo_toml_content =
-            fs::read_to_string("Cargo.toml").with_context(|| "Failed to read Cargo.toml")?;
-        let existing_patches = parse_existing_patches(&cargo_toml_content)?;
-        let all_relevant_crates: Vec<Crate> = crates
-            .iter()
-            .filter(|c| existing_patches.contains(&c.name))
-            .cloned()
-            .collect();
-
-        create_pull_request(branch_name, &all_relevant_crates)?;
-        info!("Pull request created!");
-    } else {
-        info!("Dry run complete. Changes were committed but not pushed.");
-    }
-    Ok(())
+/// How commands go through the repos.
+#[derive(Debug, Clone, Copy)]
+struct RunOptions {
+    /// Number of repos to process at the same time.
+    jobs: usize,
+    /// Stop starting new repos once one has failed.
+    fail_fast: bool,
+    /// Stash uncommitted changes before switching branches, instead of
+    /// skipping repos that have them.
+    stash: bool,
 }
 
-fn create_and_checkout_branch(branch_name: &str) -> Result<()> {
-    // Checkout the `main` branch
-    info!("Checking out the `main` branch...");
-    Cmd::new("git")
-        .args(["checkout", "main"])
-        .status()
-        .with_context(|| "Failed to checkout `main` branch")?;
+/// Runs `f` for every directory on up to `jobs` threads, returning the results
+/// in the same order as `directories`.
+///
+/// With more than one job, the output of each repo is captured and printed in
+/// one block when the repo is done, so output from different repos does not
+/// interleave.
+///
+/// With `fail_fast`, no new repos are started once one has failed, and the
+/// result of every repo that was skipped is `None`.
+fn for_each_repo<T, E, F>(
+    directories: &[Directory],
+    options: RunOptions,
+    f: F,
+) -> Vec<Option<Result<T, E>>>
+where
+    T: Send,
+    E: Send,
+    F: Fn(&Directory) -> Result<T, E> + Sync,
+{
+    let RunOptions {
+        jobs, fail_fast, ..
+    } = options;
+    let failed = AtomicBool::new(false);
+    let run = |dir| {
+        if fail_fast && failed.load(Ordering::SeqCst) {
+            return None;
+        }
+        let result = f(dir);
+        if result.is_err() {
+            failed.store(true, Ordering::SeqCst);
+        }
+        Some(result)
+    };
 
-    // Pull the latest changes from `origin/main`
-    info!("Pulling latest changes from `origin/main`...");
-    Cmd::new("git")
-        .args(["pull", "origin", "main"])
-        .status()
-        .with_context(|| "Failed to pull from `origin/main`")?;
+    if jobs <= 1 || directories.len() <= 1 {
+        return directories.iter().map(run).collect();
+    }
 
-    Cmd::new("git")
-        .args(["checkout", "-b", branch_name])
-        .status()
-        .with_context(|| "Failed to create and checkout branch")?;
-    Ok(())
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<T, E>>>> =
+        Mutex::new(directories.iter().map(|_| None).collect());
+    let print_lock = Mutex::new(());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(directories.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(dir) = directories.get(i) else {
+                    break;
+                };
+                let (result, lines) = capture_output(|| run(dir));
+                {
+                    let _guard = print_lock.lock().expect("poisoned");
+                    print_captured(lines);
+                }
+                results.lock().expect("poisoned")[i] = result;
+            });
+        }
+    });
+    results.into_inner().expect("poisoned")
 }
 
-fn cargo_update(updated_crates: &Vec<Crate>) -> anyhow::Result<()> {
-    info!("Updating...");
-    // Start building the command
-    let mut cmd = Cmd::new("cargo");
-    cmd.arg("update");
+/// Logs the repos that `for_each_repo` skipped because of `--fail-fast`.
+fn log_skipped(skipped: &[&Directory]) {
+    if !skipped.is_empty() {
+        info!("repos skipped after an earlier failure:");
+        for dir in skipped {
+            info!(
+                "\t{}",
+                dir.path.file_name().expect("checked").to_string_lossy()
+            );
+        }
+    }
+}
 
-    // Add each crate to the command with the `--package` flag
-    for krate in updated_crates {
-        info!("package {}", &krate.name);
-        cmd.arg("--package").arg(&krate.name);
+/// Turns the results of [`for_each_repo`] into a report for every repo, in
+/// the order of `directories`.
+///
+/// `report` builds the report of each repo that ran, and `branch` is recorded
+/// in it when given. The repos that were skipped get a skipped report, and are
+/// returned for [`log_skipped`].
+fn collect_reports<'a, T, E>(
+    directories: &'a [Directory],
+    results: Vec<Option<Result<T, E>>>,
+    branch: Option<&str>,
+    mut report: impl FnMut(&'a Directory, Result<T, E>) -> RepoReport,
+) -> (Vec<RepoReport>, Vec<&'a Directory>) {
+    let mut reports = vec![];
+    let mut skipped = vec![];
+    for (dir, result) in directories.iter().zip(results) {
+        let Some(result) = result else {
+            skipped.push(dir);
+            reports.push(RepoReport::skipped(dir));
+            continue;
+        };
+        let mut repo_report = report(dir, result);
+        if let Some(branch) = branch {
+            repo_report.branch = Some(branch.to_string());
+        }
+        reports.push(repo_report);
     }
+    (reports, skipped)
+}
 
-    // Execute the command
-    cmd.status()
-        .with_context(|| "Failed to run `cargo update`")?;
+/// The directory name of a repo, as shown in the summaries.
+fn dir_name(dir: &Directory) -> String {
+    dir.path
+        .file_name()
+        .expect("checked")
+        .to_string_lossy()
+        .into_owned()
+}
 
-    Ok(())
+/// A step of `patch` for a single repo, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Step {
+    BranchCreated,
+    Patched,
+    CargoUpdated,
+    DenyUpdated,
+    Committed,
+    Pushed,
+    PrCreated,
 }
 
-fn ensure_patches_in_cargo_toml(crates: &[Crate], branch_name: &str) -> Result<Vec<Crate>> {
-    let cargo_toml_path = Path::new("Cargo.toml");
-    let cargo_toml_content =
-        fs::read_to_string(cargo_toml_path).with_context(|| "Failed to read Cargo.toml")?;
+/// Progress of `patch` in a single repo.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct RepoState {
+    /// The last step that finished.
+    step: Option<Step>,
+    /// Names of the crates that were patched in Cargo.toml.
+    #[serde(default)]
+    patched: Vec<String>,
+    /// URL of the created PR.
+    pr_url: Option<String>,
+}
 
-    // Parse Cargo.toml to find referenced dependencies
-    let referenced_crates = parse_referenced_crates(&cargo_toml_content)?;
+/// Contents of the state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RunState {
+    /// Name of the branch the run was for.
+    branch_name: String,
+    /// Progress of each repo, keyed by the repo path.
+    #[serde(default)]
+    repos: BTreeMap<PathBuf, RepoState>,
+}
 
-    // Parse existing patches from [patch.crates-io]
-    let existing_patches = parse_existing_patches(&cargo_toml_content)?;
+/// The state file of a `patch` run, which is written after every step so an
+/// interrupted run can be continued with `--resume`.
+struct StateFile {
+    path: PathBuf,
+    state: Mutex<RunState>,
+}
 
-    // Open Cargo.toml for appending
-    let mut cargo_toml = fs::OpenOptions::new()
-        .append(true)
-        .open(cargo_toml_path)
-        .with_context(|| "Failed to open Cargo.toml for appending")?;
+impl StateFile {
+    /// Starts a new run, replacing the state of any previous run right away,
+    /// so a later `--resume` never continues the old one.
+    fn new(path: PathBuf, branch_name: &str) -> Result<Self> {
+        let state = RunState {
+            branch_name: branch_name.to_string(),
+            ..Default::default()
+        };
+        write_state(&path, &state)?;
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+        })
+    }
 
-    // Ensure [patch.crates-io] section exists
-    if !cargo_toml_content.contains("[patch.crates-io]") {
-        writeln!(cargo_toml, "\n[patch.crates-io]")
-            .with_context(|| "Failed to write to Cargo.toml")?;
+    /// Loads the state of a previous run to continue it.
+    fn load(path: PathBuf, branch_name: &str) -> Result<Self> {
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read state file at {}", path.display()))?;
+        let state: RunState =
+            toml::from_str(&content).with_context(|| "Failed to parse state file")?;
+        if state.branch_name != branch_name {
+            bail!(
+                "State file at {} is for branch `{}`, not `{branch_name}`",
+                path.display(),
+                state.branch_name
+            );
+        }
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+        })
     }
 
-    // Track crates that were updated
-    let mut updated_crates = Vec::new();
+    fn get(&self, repo: &Path) -> RepoState {
+        let state = self.state.lock().expect("poisoned");
+        state.repos.get(repo).cloned().unwrap_or_default()
+    }
 
-    // Add patches for crates that are referenced but not already patched
-    for crate_entry in crates {
-        if referenced_crates.contains(&crate_entry.name)
-            && !existing_patches.contains(&crate_entry.name)
-        {
-            let patch_line = if &crate_entry.name == "iroh"
-                || &crate_entry.name == "iroh-relay"
-                || &crate_entry.name == "iroh-dns-server"
-                || &crate_entry.name == "iroh-base"
-            {
-                format!(
-                    "{} = {{ git = \"{}\", branch = \"main\" }}",
-                    crate_entry.name, crate_entry.repo_url
-                )
-            } else {
-                format!(
-                    "{} = {{ git = \"{}\", branch = \"{}\" }}",
-                    crate_entry.name, crate_entry.repo_url, branch_name
-                )
-            };
-            writeln!(cargo_toml, "{}", patch_line)
-                .with_context(|| "Failed to write to Cargo.toml")?;
-            updated_crates.push(crate_entry.clone()); // Clone `crate_entry` properly
-        }
+    /// Updates the state of `repo` and writes the state file.
+    fn update(&self, repo: &Path, f: impl FnOnce(&mut RepoState)) -> Result<()> {
+        let mut state = self.state.lock().expect("poisoned");
+        f(state.repos.entry(repo.to_path_buf()).or_default());
+        write_state(&self.path, &state)
     }
 
-    Ok(updated_crates)
+    /// Records that `step` finished in `repo`.
+    fn finish(&self, repo: &Path, step: Step) -> Result<()> {
+        self.update(repo, |repo_state| repo_state.step = Some(step))
+    }
 }
 
-fn parse_referenced_crates(cargo_toml_content: &str) -> Result<HashSet<String>> {
-    let mut referenced_crates = HashSet::new();
-
-    // Parse [dependencies] and [dev-dependencies] sections
-    let toml: toml::Value =
-        toml::from_str(cargo_toml_content).with_context(|| "Failed to parse Cargo.toml")?;
+fn write_state(path: &Path, state: &RunState) -> Result<()> {
+    let content = toml::to_string(state).with_context(|| "Failed to serialize state")?;
+    fs::write(path, content)
+        .with_context(|| format!("Failed to write state file at {}", path.display()))
+}
 
-    if let Some(dependencies) = toml.get("dependencies") {
-        if let Some(deps) = dependencies.as_table() {
-            for crate_name in deps.keys() {
-                referenced_crates.insert(crate_name.to_string());
+fn patch_crates(
+    directories: &[Directory],
+    branch_name: &str,
+    crates: &[Crate],
+    execute: bool,
+    direct_only: bool,
+    state: &StateFile,
+    options: RunOptions,
+) -> Result<Vec<RepoReport>> {
+    // info!("Patching crates...");
+    let results = for_each_repo(directories, options, |dir| {
+        // A resumed run leaves its own changes uncommitted when it stops
+        // between patching Cargo.toml and committing
+        let resumed = state
+            .get(&dir.path)
+            .step
+            .is_some_and(|step| step >= Step::Patched);
+        let patch = || patch_crate(dir, branch_name, crates, execute, direct_only, state);
+        let result = if resumed {
+            patch()
+        } else {
+            with_clean_tree(&dir.path, options.stash, patch)
+        };
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+    let mut successful = vec![];
+    let mut unsuccessful = vec![];
+    let (reports, skipped) = collect_reports(
+        directories,
+        results,
+        Some(branch_name),
+        |dir, result| match result {
+            Err(e) => {
+                let report = RepoReport::failed(dir, &e);
+                unsuccessful.push((dir, e));
+                report
+            }
+            Ok(outcome) => {
+                let report = RepoReport {
+                    crates: outcome.crates.iter().map(CrateReport::from).collect(),
+                    commit: outcome.commit,
+                    pr_url: outcome.pr_url,
+                    ..RepoReport::new(dir)
+                };
+                successful.push((dir, outcome.crates));
+                report
+            }
+        },
+    );
+    if !successful.is_empty() {
+        info!("crates successfully patched:");
+        for (cr, patched) in successful {
+            let filename = cr.path.file_name().unwrap().to_string_lossy();
+            info!("\t{filename}");
+            for patched_crate in patched {
+                info!("\t\t{patched_crate}");
             }
         }
     }
 
-    if let Some(dev_dependencies) = toml.get("dev-dependencies") {
-        if let Some(deps) = dev_dependencies.as_table() {
-            for crate_name in deps.keys() {
-                referenced_crates.insert(crate_name.to_string());
-            }
+    if !unsuccessful.is_empty() {
+        info!("crates that could not be patched:");
+        let mut resumable = false;
+        for (cr, e) in unsuccessful {
+            let filename = cr.path.file_name().unwrap().to_string_lossy();
+            info!("\t{filename}: {e:#}");
+            resumable |= state.get(&cr.path).step.is_some();
+        }
+        if resumable {
+            info!(
+                "Progress was saved to {}, rerun with `--resume` to continue.",
+                state.path.display()
+            );
         }
     }
-
-    Ok(referenced_crates)
+    log_skipped(&skipped);
+    Ok(reports)
 }
 
-fn parse_existing_patches(cargo_toml_content: &str) -> Result<HashSet<String>> {
-    let mut existing_patches = HashSet::new();
+fn patch_crate(
+    directory: &Directory,
+    branch_name: &str,
+    crates: &[Crate],
+    execute: bool,
+    direct_only: bool,
+    state: &StateFile,
+) -> Result<BranchOutcome<PatchedCrate>> {
+    let repo = &directory.path;
+    let dir_name = repo.file_name().expect("checked");
+    info!("Working with repo {dir_name:?}");
+    let base_branch = base_branch(directory);
+    let remote = directory.remote();
+    let crates = &directory.crates(crates);
 
-    // Parse [patch.crates-io] section
-    let toml: toml::Value =
-        toml::from_str(cargo_toml_content).with_context(|| "Failed to parse Cargo.toml")?;
+    // Steps that finished in a previous run are skipped
+    let previous = state.get(repo);
+    let pending = |step| previous.step < Some(step);
+    if let Some(step) = previous.step {
+        info!("Resuming after step `{step:?}`");
+    }
 
-    if let Some(patch) = toml.get("patch") {
-        if let Some(crates_io) = patch.get("crates-io") {
-            if let Some(patches) = crates_io.as_table() {
-                for crate_name in patches.keys() {
-                    existing_patches.insert(crate_name.to_string());
-                }
-            }
+    if pending(Step::BranchCreated) {
+        if !branch_exists(repo, branch_name) {
+            create_and_checkout_branch(repo, remote, &base_branch, branch_name)?;
+        } else {
+            info!("Branch '{branch_name}' already exists. Checking it out.");
+            checkout_branch(repo, branch_name)?;
         }
+        state.finish(repo, Step::BranchCreated)?;
+    } else {
+        checkout_branch(repo, branch_name)?;
     }
 
-    Ok(existing_patches)
-}
-
-fn commit_changes(updated_crates: &[Crate]) -> Result<()> {
-    // Generate the commit message body (same as PR body)
-    let commit_body = format!(
-        "Updates the following dependencies to use their main branches:\n\n{}",
+    // Ensure patches are in Cargo.toml and get the list of updated crates
+    let updated_crates = if pending(Step::Patched) {
+        let updated_crates = ensure_patches_in_cargo_toml(repo, crates, branch_name, direct_only)?;
+        state.update(repo, |repo_state| {
+            repo_state.step = Some(Step::Patched);
+            repo_state.patched = updated_crates
+                .iter()
+                .map(|p| p.krate.name.clone())
+                .collect();
+        })?;
         updated_crates
-            .iter()
-            .map(|c| format!("- `{}` from `{}`", c.name, c.repo_url))
-            .collect::<Vec<_>>()
-            .join("\n")
-    );
+    } else {
+        // Cargo.toml was already written, so read back what was patched
+        patched_crates(repo, &read_cargo_toml(repo)?, crates)?
+            .into_iter()
+            .filter(|p| previous.patched.contains(&p.krate.name))
+            .collect()
+    };
 
-    // Combine the first line and body into the full commit message
-    let commit_message = format!(
-        "chore: add patch for `iroh` dependencies\n\n{}",
-        commit_body
-    );
+    // If there are updated crates, update deny.toml if it exists
+    if !updated_crates.is_empty() {
+        for patched in updated_crates.iter().filter(|p| p.transitive) {
+            info!(
+                "Patching `{}` because it is used transitively",
+                patched.krate.name
+            );
+        }
+
+        // Run `cargo update` to update dependencies
+        if pending(Step::CargoUpdated) {
+            info!("Running `cargo update`...");
+            let krates: Vec<Crate> = updated_crates.iter().map(|p| p.krate.clone()).collect();
+            cargo_update(repo, &krates)?;
+            state.finish(repo, Step::CargoUpdated)?;
+        }
 
-    let mut args = vec!["add", "Cargo.toml", "Cargo.lock"];
+        // Check if deny.toml exists and update it
+        if pending(Step::DenyUpdated) {
+            if directory.skip_deny {
+                info!("Skipping deny.toml for this repo.");
+            } else {
+                update_deny_toml(repo, &updated_crates)?;
+            }
+            state.finish(repo, Step::DenyUpdated)?;
+        }
 
-    let deny_toml_path = Path::new("deny.toml");
-    // Check if deny.toml exists
-    if deny_toml_path.exists() {
-        args.push("deny.toml");
+        // Commit changes
+        if pending(Step::Committed) {
+            let commit_message = directory.patch_commit_message(branch_name, &updated_crates);
+            commit_files(repo, &commit_message, &directory.commit, &[])?;
+            state.finish(repo, Step::Committed)?;
+        }
     }
 
-    // Stage the changes
-    Cmd::new("git")
-        .args(args)
-        .status()
-        .with_context(|| "Failed to stage changes")?;
+    // Push and create PR if `execute` is true
+    let mut pr_url = previous.pr_url.clone();
+    if execute {
+        if pending(Step::Pushed) {
+            push_branch(repo, remote, branch_name)?;
+            state.finish(repo, Step::Pushed)?;
+        }
 
-    // Commit the changes with the formatted message
-    Cmd::new("git")
-        .args(["commit", "-m", &commit_message])
-        .status()
-        .with_context(|| "Failed to commit changes")?;
+        if pending(Step::PrCreated) {
+            // Get all crates in [patch.crates-io] that are in our list of crates
+            let all_relevant_crates = patched_crates(repo, &read_cargo_toml(repo)?, crates)?;
 
-    Ok(())
+            // Generate the PR with the list of patched dependencies
+            let pr = directory.patch_pull_request(branch_name, &all_relevant_crates);
+            let url = create_pull_request(repo, &base_branch, branch_name, &pr)?;
+            state.update(repo, |repo_state| {
+                repo_state.step = Some(Step::PrCreated);
+                repo_state.pr_url = Some(url.clone());
+            })?;
+            pr_url = Some(url);
+        } else if let Some(pr_url) = &pr_url {
+            info!("Pull request was already created: {pr_url}");
+        }
+    } else {
+        info!("Dry run complete. Changes were committed but not pushed.");
+    }
+    Ok(BranchOutcome {
+        crates: updated_crates,
+        commit: Some(head_commit(repo)?),
+        pr_url,
+    })
 }
 
-fn push_branch(branch_name: &str) -> Result<()> {
-    Cmd::new("git")
-        .args(["push", "origin", branch_name])
-        .status()
-        .with_context(|| "Failed to push branch")?;
-    Ok(())
+/// Returns the crates from `crates` that are patched in the given Cargo.toml
+/// of `repo`.
+fn patched_crates(
+    repo: &Path,
+    cargo_toml_content: &str,
+    crates: &[Crate],
+) -> Result<Vec<PatchedCrate>> {
+    let existing_patches = parse_existing_patches(cargo_toml_content)?;
+    let referenced_crates = parse_workspace_referenced_crates(repo, cargo_toml_content)?;
+    Ok(crates
+        .iter()
+        .filter_map(|c| {
+            let git_ref = existing_patches.get(&c.name)?;
+            Some(PatchedCrate {
+                krate: c.clone(),
+                git_ref: git_ref.clone(),
+                transitive: !referenced_crates.contains(&c.name),
+            })
+        })
+        .collect())
 }
 
-fn create_pull_request(branch_name: &str, relevant_crates: &[Crate]) -> Result<()> {
-    // Generate the PR body with the list of patched dependencies
-    let pr_body = format!(
-        "This PR updates the following dependencies to their latest versions:\n\n{}",
-        relevant_crates
-            .iter()
-            .map(|c| format!("- `{}` from `{}`", c.name, c.repo_url))
-            .collect::<Vec<_>>()
-            .join("\n")
-    );
-
-    Cmd::new("gh")
-        .args([
-            "pr",
-            "create",
-            "--title",
-            "chore: release prep",
-            "--body",
-            &pr_body,
-            "--base",
-            "main",
-            "--head",
-            branch_name,
-        ])
-        .status()
-        .with_context(|| "Failed to create pull request")?;
-    Ok(())
+fn read_cargo_toml(repo: &Path) -> Result<String> {
+    fs::read_to_string(repo.join("Cargo.toml")).with_context(|| "Failed to read Cargo.toml")
 }
 
-fn cleanup_branches(directories: &[PathBuf]) -> Result<()> {
-    info!("Cleaning up patch-iroh-main branches in all directories...");
-    for dir in directories {
-        info!("Cleaning up in {}", dir.display());
-        if std::env::set_current_dir(dir).is_ok() {
-            Cmd::new("git")
-                .args(["checkout", "main"])
-                .status()
-                .with_context(|| "Failed to checkout `main` branch")?;
+/// A command that ran, but exited with a non-zero status.
+#[derive(Debug)]
+struct CommandError {
+    /// The command line that was run, like `git pull origin main`.
+    command: String,
+    /// The directory the command ran in.
+    directory: PathBuf,
+    status: ExitStatus,
+    /// What the command printed before failing, which some commands like
+    /// `gh pr checks` still fill in.
+    stdout: String,
+    stderr: String,
+}
 
-            Cmd::new("git")
-                .args(["branch", "-D", "patch-iroh-main"])
-                .status()
-                .ok();
-            Cmd::new("git")
-                .args(["push", "origin", "--delete", "patch-iroh-main"])
-                .status()
-                .ok();
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` failed in {} ({})",
+            self.command,
+            self.directory.display(),
+            self.status
+        )?;
+        // Point at the first `error:` or `fatal:` line, falling back to the
+        // last line of stderr
+        let lines = || self.stderr.lines().map(str::trim).filter(|l| !l.is_empty());
+        let message = lines()
+            .find(|l| l.starts_with("error") || l.starts_with("fatal"))
+            .or_else(|| lines().next_back());
+        if let Some(message) = message {
+            write!(f, ": {message}")?;
         }
+        Ok(())
     }
-    info!("Branches cleaned up.");
-    Ok(())
 }
 
-fn update_and_check(directories: &[PathBuf], crates: &Vec<Crate>) -> Result<()> {
-    info!("");
-    let mut successes = vec![];
-    let mut main_failures = vec![];
-    let mut update_failures = vec![];
-    let mut check_failures = vec![];
-    for dir in directories {
-        let dir_name = dir.file_name().expect("checked").to_str().expect("checked");
-        println!("Updating and checking {dir_name} on `main` branch");
-        if std::env::set_current_dir(dir).is_ok() {
-            if let Err(e) = checkout_and_pull() {
-                error!("{e:?}");
-                main_failures.push(dir_name);
-                continue;
-            };
-            let referenced_crates = match list_relevant_crates(crates) {
-                Err(e) => {
-                    error!("{e:?}");
-                    update_failures.push(dir_name);
-                    continue;
-                }
-                Ok(r) => r,
-            };
-            if let Err(e) = cargo_update(&referenced_crates) {
-                error!("Unable to run `cargo update` on {dir_name}: {e:?}");
-                update_failures.push(dir_name);
-                continue;
-            }
-            if let Err(e) = cargo_check() {
-                error!("Error running `cargo check` for {dir_name}: {e:?}");
-                check_failures.push(dir_name);
-                continue;
-            }
-            successes.push(dir_name);
-        }
+impl std::error::Error for CommandError {}
+
+/// Runs the command to completion, capturing its output.
+///
+/// Returns stdout when the command succeeds, and a [`CommandError`] when it
+/// exits with a non-zero status.
+fn run_command(cmd: &mut Cmd) -> Result<String> {
+    run_command_output(cmd).map(|(stdout, _stderr)| stdout)
+}
+
+/// Like [`run_command`], but returns stderr along with stdout.
+fn run_command_output(cmd: &mut Cmd) -> Result<(String, String)> {
+    let command = command_line(cmd);
+    let directory = match cmd.get_current_dir() {
+        Some(dir) => dir.to_path_buf(),
+        None => std::env::current_dir().unwrap_or_default(),
+    };
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to run `{command}`"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    for line in stdout.lines().chain(stderr.lines()) {
+        debug!("\t{line}");
     }
 
-    if !successes.is_empty() {
-        info!("repos successfully updated and checked:");
-        for repo in successes {
-            info!("\t{repo}");
+    if !output.status.success() {
+        return Err(CommandError {
+            command,
+            directory,
+            status: output.status,
+            stdout,
+            stderr,
         }
+        .into());
     }
+    Ok((stdout, stderr))
+}
 
-    if !main_failures.is_empty() {
-        info!("repos that could not checkout `main`:");
-        for repo in main_failures {
-            info!("\t{repo}");
-        }
+/// Renders the command as a single line for logs and errors, eliding
+/// multi-line arguments like PR bodies.
+fn command_line(cmd: &Cmd) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.contains('\n') {
+                "<...>".to_string()
+            } else {
+                arg.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns the name of the branch checked out in `repo`, or `HEAD` when it is
+/// detached.
+fn current_branch(repo: &Path) -> Result<String> {
+    let branch =
+        run_command(
+            Cmd::new("git")
+                .current_dir(repo)
+                .args(["rev-parse", "--abbrev-ref", "HEAD"]),
+        )
+        .with_context(|| "Failed to get the current branch")?;
+    Ok(branch.trim().to_string())
+}
+
+/// Whether `repo` has uncommitted changes, including untracked files when
+/// `untracked` is set.
+fn has_uncommitted_changes(repo: &Path, untracked: bool) -> Result<bool> {
+    let untracked_files = if untracked { "all" } else { "no" };
+    let status = run_command(Cmd::new("git").current_dir(repo).args([
+        "status",
+        "--porcelain",
+        &format!("--untracked-files={untracked_files}"),
+    ]))
+    .with_context(|| "Failed to get the status of the working tree")?;
+    Ok(!status.trim().is_empty())
+}
+
+/// Uncommitted changes that were stashed by [`stash_changes`].
+struct Stash {
+    /// The branch, or commit when detached, the changes were made on.
+    checkout: String,
+    /// The stash commit, to point at when the changes can't be restored.
+    commit: String,
+}
+
+/// Makes sure `repo` has no uncommitted or untracked changes before it
+/// switches branches. With `stash`, the changes are stashed, to be put back
+/// with [`restore_changes`], otherwise a dirty tree is an error.
+fn stash_changes(repo: &Path, stash: bool) -> Result<Option<Stash>> {
+    if !has_uncommitted_changes(repo, true)? {
+        return Ok(None);
+    }
+    if !stash {
+        bail!(
+            "Skipping {}: it has uncommitted changes, commit them or rerun with `--stash`",
+            repo.display()
+        );
+    }
+    let mut checkout = current_branch(repo)?;
+    if checkout == "HEAD" {
+        checkout = head_commit(repo)?;
     }
+    info!("Stashing uncommitted changes on `{checkout}`");
+    run_command(Cmd::new("git").current_dir(repo).args([
+        "stash",
+        "push",
+        "--include-untracked",
+        "--message",
+        "patch-crates: uncommitted changes",
+    ]))
+    .with_context(|| "Failed to stash uncommitted changes")?;
+    let commit = run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["rev-parse", "stash@{0}"]),
+    )
+    .with_context(|| "Failed to get the stash commit")?;
+    Ok(Some(Stash {
+        checkout,
+        commit: commit.trim().to_string(),
+    }))
+}
 
-    if !update_failures.is_empty() {
-        info!("repos that did not run `cargo update` successfully:");
-        for repo in update_failures {
-            info!("\t{repo}");
-        }
+/// Checks out the branch the stashed changes were made on and pops them.
+///
+/// When the command left its own changes in the tree, like a partial patch
+/// after a failure, checking out would carry them over to the original branch,
+/// so the stash is left in place instead.
+fn restore_changes(repo: &Path, stash: Stash) -> Result<()> {
+    let checkout = &stash.checkout;
+    if has_uncommitted_changes(repo, true)? {
+        warn!(
+            "{} has uncommitted changes from this run, so the stashed changes were left in \
+             the stash ({}), restore them on `{checkout}` with `git stash pop`",
+            repo.display(),
+            stash.commit
+        );
+        return Ok(());
     }
+    info!("Restoring stashed changes on `{checkout}`");
+    run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["checkout", checkout]),
+    )
+    .with_context(|| {
+        format!("Failed to checkout `{checkout}`, restore the changes with `git stash pop`")
+    })?;
+    run_command(Cmd::new("git").current_dir(repo).args(["stash", "pop"])).with_context(|| {
+        "Failed to restore the stashed changes, restore them with `git stash pop`"
+    })?;
+    Ok(())
+}
 
-    if !check_failures.is_empty() {
-        info!("repos that had an error in `cargo check`:");
-        for repo in check_failures {
-            info!("\t{repo}");
+/// Runs `f` on a clean working tree, see [`stash_changes`].
+fn with_clean_tree<T>(repo: &Path, stash: bool, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let stashed = stash_changes(repo, stash)?;
+    let result = f();
+    let Some(stashed) = stashed else {
+        return result;
+    };
+    match (result, restore_changes(repo, stashed)) {
+        (result, Ok(())) => result,
+        (Ok(_), Err(e)) => Err(e),
+        // Report the error that stopped the command, but don't lose track of
+        // the stashed changes
+        (Err(e), Err(restore_error)) => {
+            error!("{restore_error:#}");
+            Err(e)
         }
     }
-    Ok(())
 }
 
-fn list_relevant_crates(crates: &[Crate]) -> Result<Vec<Crate>> {
-    let cargo_toml_path = Path::new("Cargo.toml");
-    let cargo_toml_content =
-        fs::read_to_string(cargo_toml_path).with_context(|| "Failed to read Cargo.toml")?;
+/// Returns the SHA of the commit checked out in `repo`.
+fn head_commit(repo: &Path) -> Result<String> {
+    let sha = run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["rev-parse", "HEAD"]),
+    )
+    .with_context(|| "Failed to get the current commit")?;
+    Ok(sha.trim().to_string())
+}
 
-    // Parse Cargo.toml to find referenced dependencies
-    let referenced_crates = parse_referenced_crates(&cargo_toml_content)?;
-    let mut relevant_crates = vec![];
-    for krate in crates {
-        if referenced_crates.contains(&krate.name) {
-            relevant_crates.push(krate.clone());
-        }
+/// Check if the branch already exists
+fn branch_exists(repo: &Path, branch_name: &str) -> bool {
+    Cmd::new("git")
+        .current_dir(repo)
+        .args(["rev-parse", "--verify", "--quiet", branch_name])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// The branch new branches are created from and PRs are opened against: the
+/// directory's `base_branch` if set, otherwise the branch `origin/HEAD`
+/// points to.
+fn base_branch(directory: &Directory) -> String {
+    if let Some(base_branch) = &directory.base_branch {
+        return base_branch.clone();
     }
-    Ok(relevant_crates)
+    let remote = directory.remote();
+    detect_default_branch(&directory.path, remote).unwrap_or_else(|| {
+        warn!(
+            "Could not detect the default branch of {} from `{remote}/HEAD`, assuming `main`. \
+             Run `git remote set-head {remote} --auto` or set `base_branch` for this directory.",
+            directory.path.display()
+        );
+        "main".to_string()
+    })
 }
 
-fn cargo_check() -> Result<()> {
-    let output = Cmd::new("cargo")
-        .args(["check", "--all-targets", "--all-features"])
+fn detect_default_branch(repo: &Path, remote: &str) -> Option<String> {
+    let output = Cmd::new("git")
+        .current_dir(repo)
+        .args([
+            "symbolic-ref",
+            "--short",
+            &format!("refs/remotes/{remote}/HEAD"),
+        ])
         .output()
-        .with_context(|| "Failed to run `cargo check`")?;
+        .ok()?;
     if !output.status.success() {
-        bail!("`cargo check` failed with errors");
+        return None;
     }
+    let head = String::from_utf8(output.stdout).ok()?;
+    head.trim()
+        .strip_prefix(&format!("{remote}/"))
+        .map(str::to_string)
+}
+
+fn create_and_checkout_branch(
+    repo: &Path,
+    remote: &str,
+    base_branch: &str,
+    branch_name: &str,
+) -> Result<()> {
+    checkout_and_pull(repo, remote, base_branch)?;
+
+    run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["checkout", "-b", branch_name]),
+    )
+    .with_context(|| "Failed to create and checkout branch")?;
     Ok(())
 }
 
-fn checkout_and_pull() -> Result<()> {
-    info!("Checking out `main`");
-    // Checkout main
-    Cmd::new("git")
-        .args(["checkout", "main"])
-        .status()
-        .with_context(|| "Failed to checkout `main`")?;
-    // Pull the latest changes from `origin/main`
-    info!("Pulling latest changes from `origin/main`...");
-    Cmd::new("git")
-        .args(["pull", "origin", "main"])
-        .status()
-        .with_context(|| "Failed to pull from `origin/main`")?;
+fn checkout_branch(repo: &Path, branch_name: &str) -> Result<()> {
+    run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["checkout", branch_name]),
+    )
+    .with_context(|| format!("Failed to checkout `{branch_name}`"))?;
     Ok(())
 }
 
-fn reset(directories: &[PathBuf]) -> Result<()> {
-    let mut failures = vec![];
-    let mut successes = vec![];
-    for dir in directories {
-        let dir_name = dir.file_name().expect("checked").to_str().expect("checked");
-        println!("Reseting {dir_name}");
-        if std::env::set_current_dir(dir).is_ok() {
-            if let Err(e) = Cmd::new("git")
-                .arg("reset")
-                .arg("--hard")
-                .status()
-                .with_context(|| "Failed to run `cargo reset --hard`")
-            {
-                error!("{e:?}");
-                failures.push(dir_name);
-                continue;
-            }
-            successes.push(dir_name);
-        }
+fn cargo_update(repo: &Path, updated_crates: &[Crate]) -> anyhow::Result<()> {
+    info!("Updating...");
+    for krate in updated_crates {
+        info!("package {}", &krate.name);
     }
 
-    if !successes.is_empty() {
-        info!("repos successfully reset:");
-        for repo in successes {
-            info!("\t{repo}");
+    // Execute the command
+    run_command(&mut cargo_update_command(repo, updated_crates))
+        .with_context(|| "Failed to run `cargo update`")?;
+
+    Ok(())
+}
+
+fn cargo_update_command(repo: &Path, updated_crates: &[Crate]) -> Cmd {
+    // Start building the command
+    let mut cmd = Cmd::new("cargo");
+    cmd.current_dir(repo).arg("update");
+
+    // Add each crate to the command with the `--package` flag
+    for krate in updated_crates {
+        cmd.arg("--package").arg(&krate.name);
+    }
+    cmd
+}
+
+fn ensure_patches_in_cargo_toml(
+    repo: &Path,
+    crates: &[Crate],
+    branch_name: &str,
+    direct_only: bool,
+) -> Result<Vec<PatchedCrate>> {
+    let cargo_toml_path = repo.join("Cargo.toml");
+    let cargo_toml_content =
+        fs::read_to_string(&cargo_toml_path).with_context(|| "Failed to read Cargo.toml")?;
+    let (cargo_toml, updated_crates) =
+        add_patches(repo, &cargo_toml_content, crates, branch_name, direct_only)?;
+
+    if !updated_crates.is_empty() {
+        fs::write(&cargo_toml_path, cargo_toml).with_context(|| "Failed to write Cargo.toml")?;
+    }
+
+    Ok(updated_crates)
+}
+
+/// Adds patches to the given Cargo.toml of `repo` for the crates it uses but
+/// does not patch yet. Returns the new Cargo.toml and the crates that were
+/// added.
+fn add_patches(
+    repo: &Path,
+    cargo_toml_content: &str,
+    crates: &[Crate],
+    branch_name: &str,
+    direct_only: bool,
+) -> Result<(String, Vec<PatchedCrate>)> {
+    // Parse Cargo.toml and any workspace members to find referenced dependencies
+    let referenced_crates = parse_workspace_referenced_crates(repo, cargo_toml_content)?;
+    // A repo never patches the crates it provides itself
+    let workspace_packages = workspace_package_names(repo, cargo_toml_content)?;
+
+    // Parse existing patches from [patch.crates-io]
+    let existing_patches = parse_existing_patches(cargo_toml_content)?;
+
+    // Parse Cargo.lock to find crates that are only used transitively
+    let cargo_lock_path = repo.join("Cargo.lock");
+    let locked_crates = if direct_only {
+        HashSet::new()
+    } else if cargo_lock_path.exists() {
+        let cargo_lock_content =
+            fs::read_to_string(&cargo_lock_path).with_context(|| "Failed to read Cargo.lock")?;
+        parse_locked_crates(&cargo_lock_content)?
+    } else {
+        info!("No Cargo.lock file found. Only patching direct dependencies.");
+        HashSet::new()
+    };
+
+    // Parse Cargo.toml again, keeping comments and ordering, so we can edit it
+    let mut cargo_toml: DocumentMut = cargo_toml_content
+        .parse()
+        .with_context(|| "Failed to parse Cargo.toml")?;
+
+    // Track crates that were updated
+    let mut updated_crates = Vec::new();
+
+    // Add patches for crates that are used but not already patched
+    for crate_entry in crates {
+        if existing_patches.contains_key(&crate_entry.name)
+            || workspace_packages.contains(&crate_entry.name)
+        {
+            continue;
+        }
+        let transitive = if referenced_crates.contains(&crate_entry.name) {
+            false
+        } else if locked_crates.contains(&crate_entry.name) {
+            true
+        } else {
+            continue;
+        };
+
+        let git_ref = crate_entry.git_ref(branch_name);
+        insert_patch(&mut cargo_toml, crate_entry, &git_ref)?;
+        updated_crates.push(PatchedCrate {
+            krate: crate_entry.clone(),
+            git_ref: Some(git_ref),
+            transitive,
+        });
+    }
+
+    Ok((cargo_toml.to_string(), updated_crates))
+}
+
+/// Returns the `[patch.crates-io]` table of the given Cargo.toml, creating it
+/// at the end of the document if it does not exist yet.
+fn crates_io_patch_table(cargo_toml: &mut DocumentMut) -> Result<&mut dyn TableLike> {
+    let patch = cargo_toml
+        .entry("patch")
+        .or_insert_with(|| {
+            // Only render the `[patch.crates-io]` header, not a bare `[patch]`
+            let mut patch = Table::new();
+            patch.set_implicit(true);
+            Item::Table(patch)
+        })
+        .as_table_like_mut()
+        .with_context(|| "`patch` in Cargo.toml is not a table")?;
+
+    patch
+        .entry("crates-io")
+        .or_insert(Item::Table(Table::new()))
+        .as_table_like_mut()
+        .with_context(|| "`patch.crates-io` in Cargo.toml is not a table")
+}
+
+/// Adds a `name = { git = "...", <ref> = "..." }` entry to `[patch.crates-io]`.
+///
+/// The entry is keyed by the package name, since that is what cargo matches
+/// patches against, even when a dependency renames the crate.
+fn insert_patch(cargo_toml: &mut DocumentMut, krate: &Crate, git_ref: &GitRef) -> Result<()> {
+    let mut patch = InlineTable::new();
+    patch.insert("git", krate.repo_url.as_str().into());
+    patch.insert(git_ref.key(), git_ref.value().into());
+
+    crates_io_patch_table(cargo_toml)?.insert(&krate.name, toml_edit::value(patch));
+    Ok(())
+}
+
+/// Dependency tables that can reference a crate, either at the top level of a
+/// manifest or inside one of its `[target.'cfg(..)']` tables.
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+fn parse_referenced_crates(cargo_toml_content: &str) -> Result<HashSet<String>> {
+    let mut referenced_crates = HashSet::new();
+
+    let toml: toml::Value =
+        toml::from_str(cargo_toml_content).with_context(|| "Failed to parse Cargo.toml")?;
+
+    // Collect every dependency table: the top-level ones, the ones under each
+    // `[target.'cfg(..)']`, and `[workspace.dependencies]`
+    let mut dependency_tables = vec![];
+    for table in DEPENDENCY_TABLES {
+        dependency_tables.extend(toml.get(table));
+    }
+    if let Some(targets) = toml.get("target").and_then(|t| t.as_table()) {
+        for target in targets.values() {
+            for table in DEPENDENCY_TABLES {
+                dependency_tables.extend(target.get(table));
+            }
+        }
+    }
+    if let Some(workspace) = toml.get("workspace") {
+        dependency_tables.extend(workspace.get("dependencies"));
+    }
+
+    for dependencies in dependency_tables {
+        if let Some(deps) = dependencies.as_table() {
+            for (key, dep) in deps {
+                // Path dependencies, like the ones between workspace members,
+                // don't come from crates.io, so a patch would not apply to them
+                if dep.get("path").is_some() {
+                    continue;
+                }
+                referenced_crates.insert(dependency_package_name(key, dep).to_string());
+            }
+        }
+    }
+
+    Ok(referenced_crates)
+}
+
+/// The name of the package a dependency entry refers to.
+///
+/// This is the `package` key for renamed dependencies like
+/// `iroh_net = { package = "iroh", version = "..." }`, and the entry's key
+/// otherwise.
+fn dependency_package_name<'a>(key: &'a str, dep: &'a toml::Value) -> &'a str {
+    dep.get("package").and_then(|p| p.as_str()).unwrap_or(key)
+}
+
+/// The names of the packages in the workspace at `directory`, including the
+/// root package.
+fn workspace_package_names(directory: &Path, cargo_toml_content: &str) -> Result<HashSet<String>> {
+    let package_name = |content: &str| -> Result<Option<String>> {
+        let toml: toml::Value = toml::from_str(content)?;
+        Ok(toml
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(|name| name.as_str())
+            .map(str::to_string))
+    };
+
+    let mut names = HashSet::new();
+    names.extend(package_name(cargo_toml_content).with_context(|| "Failed to parse Cargo.toml")?);
+    for manifest in workspace_member_manifests(directory, cargo_toml_content)? {
+        let content = fs::read_to_string(&manifest)
+            .with_context(|| format!("Failed to read {}", manifest.display()))?;
+        names.extend(
+            package_name(&content)
+                .with_context(|| format!("Failed to parse {}", manifest.display()))?,
+        );
+    }
+    Ok(names)
+}
+
+/// Returns the crates referenced by the manifest in `directory`, along with
+/// the crates referenced by each of its workspace members. The workspace's own
+/// packages are left out.
+fn parse_workspace_referenced_crates(
+    directory: &Path,
+    cargo_toml_content: &str,
+) -> Result<HashSet<String>> {
+    let mut referenced_crates = parse_referenced_crates(cargo_toml_content)?;
+
+    for manifest in workspace_member_manifests(directory, cargo_toml_content)? {
+        let content = fs::read_to_string(&manifest)
+            .with_context(|| format!("Failed to read {}", manifest.display()))?;
+        let member_crates = parse_referenced_crates(&content)
+            .with_context(|| format!("Failed to parse {}", manifest.display()))?;
+        referenced_crates.extend(member_crates);
+    }
+    for package in workspace_package_names(directory, cargo_toml_content)? {
+        referenced_crates.remove(&package);
+    }
+
+    Ok(referenced_crates)
+}
+
+/// Returns the manifest paths of every `[workspace] members` entry, skipping
+/// anything in `exclude` and the root package itself.
+fn workspace_member_manifests(directory: &Path, cargo_toml_content: &str) -> Result<Vec<PathBuf>> {
+    let toml: toml::Value =
+        toml::from_str(cargo_toml_content).with_context(|| "Failed to parse Cargo.toml")?;
+
+    let Some(workspace) = toml.get("workspace") else {
+        return Ok(vec![]);
+    };
+    let patterns = |key: &str| -> Vec<String> {
+        workspace
+            .get(key)
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect()
+    };
+
+    let mut excluded = HashSet::new();
+    for pattern in patterns("exclude") {
+        excluded.extend(expand_member_pattern(directory, &pattern)?);
+    }
+
+    let mut manifests = vec![];
+    for pattern in patterns("members") {
+        for member_dir in expand_member_pattern(directory, &pattern)? {
+            let manifest = member_dir.join("Cargo.toml");
+            if member_dir == directory
+                || excluded.contains(&member_dir)
+                || !manifest.is_file()
+                || manifests.contains(&manifest)
+            {
+                continue;
+            }
+            manifests.push(manifest);
+        }
+    }
+    Ok(manifests)
+}
+
+/// Expands a `workspace.members` entry relative to `directory`. Path
+/// components may contain `*` and `?` wildcards, like cargo allows.
+fn expand_member_pattern(directory: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![directory.to_path_buf()];
+    for component in Path::new(pattern).components() {
+        let component = match component {
+            Component::CurDir => continue,
+            Component::Normal(c) => c.to_string_lossy(),
+            other => {
+                paths = paths.into_iter().map(|p| p.join(other)).collect();
+                continue;
+            }
+        };
+        if !component.contains(['*', '?']) {
+            paths = paths.into_iter().map(|p| p.join(&*component)).collect();
+            continue;
+        }
+
+        let mut expanded = vec![];
+        for path in paths {
+            let Ok(entries) = fs::read_dir(&path) else {
+                continue;
+            };
+            for entry in entries {
+                let entry = entry.with_context(|| format!("Failed to read {}", path.display()))?;
+                let name = entry.file_name();
+                if entry.path().is_dir() && wildcard_match(&component, &name.to_string_lossy()) {
+                    expanded.push(entry.path());
+                }
+            }
+        }
+        expanded.sort();
+        paths = expanded;
+    }
+    Ok(paths)
+}
+
+/// Matches `name` against a pattern where `*` matches any run of characters
+/// and `?` matches exactly one.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+            Some(('?', rest)) => !name.is_empty() && matches(rest, &name[1..]),
+            Some((c, rest)) => name.first() == Some(c) && matches(rest, &name[1..]),
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
+/// Returns the names of the packages in Cargo.lock that come from a registry
+/// or git source, which is every crate in the resolved dependency graph
+/// outside of the workspace itself.
+fn parse_locked_crates(cargo_lock_content: &str) -> Result<HashSet<String>> {
+    let mut locked_crates = HashSet::new();
+
+    let lock: toml::Value =
+        toml::from_str(cargo_lock_content).with_context(|| "Failed to parse Cargo.lock")?;
+
+    if let Some(packages) = lock.get("package").and_then(|p| p.as_array()) {
+        for package in packages {
+            if package.get("source").is_none() {
+                continue;
+            }
+            if let Some(name) = package.get("name").and_then(|n| n.as_str()) {
+                locked_crates.insert(name.to_string());
+            }
+        }
+    }
+
+    Ok(locked_crates)
+}
+
+/// Returns the crates in `[patch.crates-io]`, along with the git ref each
+/// patch points to, if any.
+fn parse_existing_patches(cargo_toml_content: &str) -> Result<HashMap<String, Option<GitRef>>> {
+    let mut existing_patches = HashMap::new();
+
+    // Parse [patch.crates-io] section
+    let toml: toml::Value =
+        toml::from_str(cargo_toml_content).with_context(|| "Failed to parse Cargo.toml")?;
+
+    if let Some(patch) = toml.get("patch") {
+        if let Some(crates_io) = patch.get("crates-io") {
+            if let Some(patches) = crates_io.as_table() {
+                for (key, patch) in patches {
+                    existing_patches.insert(
+                        dependency_package_name(key, patch).to_string(),
+                        GitRef::from_dependency(patch),
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(existing_patches)
+}
+
+fn patch_commit_body(updated_crates: &[PatchedCrate]) -> String {
+    format!(
+        "Updates the following dependencies to use their git versions:\n\n{}",
+        crate_list(updated_crates)
+    )
+}
+
+fn patch_pr_body(patched_crates: &[PatchedCrate]) -> String {
+    format!(
+        "This PR updates the following dependencies to their latest versions:\n\n{}",
+        crate_list(patched_crates)
+    )
+}
+
+/// A markdown list of `patched_crates` and their git refs.
+fn crate_list(patched_crates: &[PatchedCrate]) -> String {
+    patched_crates
+        .iter()
+        .map(|c| format!("- {c}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Stages Cargo.toml, Cargo.lock, deny.toml (if it exists) and any
+/// `extra_paths`, and commits them with the given message.
+fn commit_files(
+    repo: &Path,
+    commit_message: &str,
+    config: &CommitConfig,
+    extra_paths: &[PathBuf],
+) -> Result<()> {
+    let [mut add, mut commit] = commit_commands(repo, commit_message, config, extra_paths)?;
+
+    // Stage the changes
+    run_command(&mut add).with_context(|| "Failed to stage changes")?;
+
+    // Commit the changes with the formatted message
+    run_command(&mut commit).with_context(|| "Failed to commit changes")?;
+
+    Ok(())
+}
+
+/// The `git add` and `git commit` commands run by [`commit_files`].
+fn commit_commands(
+    repo: &Path,
+    commit_message: &str,
+    config: &CommitConfig,
+    extra_paths: &[PathBuf],
+) -> Result<[Cmd; 2]> {
+    let mut args = vec!["add", "Cargo.toml"];
+
+    // Libraries often don't track their lock file, and staging an ignored
+    // file is an error
+    let cargo_lock_ignored = Cmd::new("git")
+        .current_dir(repo)
+        .args(["check-ignore", "-q", "Cargo.lock"])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if repo.join("Cargo.lock").exists() && !cargo_lock_ignored {
+        args.push("Cargo.lock");
+    }
+
+    // Check if deny.toml exists
+    if repo.join("deny.toml").exists() {
+        args.push("deny.toml");
+    }
+    for path in extra_paths {
+        args.push(path.to_str().with_context(|| "Path is not valid UTF-8")?);
+    }
+
+    let mut add = Cmd::new("git");
+    add.current_dir(repo).args(args);
+    let mut commit = Cmd::new("git");
+    commit.current_dir(repo);
+    if let Some(format) = &config.signing_format {
+        commit.args(["-c", &format!("gpg.format={format}")]);
+    }
+    commit.args(["commit", "-m", commit_message]);
+    if config.signoff.unwrap_or(false) {
+        commit.arg("--signoff");
+    }
+    if config.sign.unwrap_or(config.signing_key.is_some()) {
+        match &config.signing_key {
+            Some(key) => commit.arg(format!("--gpg-sign={key}")),
+            None => commit.arg("--gpg-sign"),
+        };
+    }
+    if let Some(author) = &config.author {
+        commit.args(["--author", author]);
+    }
+    Ok([add, commit])
+}
+
+fn push_branch(repo: &Path, remote: &str, branch_name: &str) -> Result<()> {
+    run_command(&mut push_command(repo, remote, branch_name))
+        .with_context(|| "Failed to push branch")?;
+    Ok(())
+}
+
+fn push_command(repo: &Path, remote: &str, branch_name: &str) -> Cmd {
+    let mut cmd = Cmd::new("git");
+    cmd.current_dir(repo).args(["push", remote, branch_name]);
+    cmd
+}
+
+/// Opens a PR for `branch_name`, or updates the body of the PR that is
+/// already open for it, so reruns don't fail. Returns the URL of the PR.
+fn create_pull_request(
+    repo: &Path,
+    base_branch: &str,
+    branch_name: &str,
+    pr: &PullRequest,
+) -> Result<String> {
+    if let Some(url) = find_open_pull_request(repo, branch_name)? {
+        run_command(&mut edit_pull_request_command(repo, &url, pr))
+            .with_context(|| format!("Failed to update {url}"))?;
+        info!("Pull request updated: {url}");
+        return Ok(url);
+    }
+    let mut cmd = pull_request_command(repo, base_branch, branch_name, pr);
+    let output = run_command(&mut cmd).with_context(|| "Failed to create pull request")?;
+    // `gh pr create` prints the URL of the new PR
+    let url = output.trim().to_string();
+    info!("Pull request created: {url}");
+    Ok(url)
+}
+
+fn pull_request_command(
+    repo: &Path,
+    base_branch: &str,
+    branch_name: &str,
+    pr: &PullRequest,
+) -> Cmd {
+    let mut cmd = Cmd::new("gh");
+    cmd.current_dir(repo).args([
+        "pr",
+        "create",
+        "--title",
+        &pr.title,
+        "--body",
+        &pr.body,
+        "--base",
+        base_branch,
+        "--head",
+        branch_name,
+    ]);
+    for label in &pr.labels {
+        cmd.args(["--label", label]);
+    }
+    for reviewer in &pr.reviewers {
+        cmd.args(["--reviewer", reviewer]);
+    }
+    for assignee in &pr.assignees {
+        cmd.args(["--assignee", assignee]);
+    }
+    if let Some(milestone) = &pr.milestone {
+        cmd.args(["--milestone", milestone]);
+    }
+    if pr.draft {
+        cmd.arg("--draft");
+    }
+    cmd
+}
+
+/// Updates an open PR to match `pr`. Whether it is a draft is left alone.
+fn edit_pull_request_command(repo: &Path, url: &str, pr: &PullRequest) -> Cmd {
+    let mut cmd = Cmd::new("gh");
+    cmd.current_dir(repo)
+        .args(["pr", "edit", url, "--title", &pr.title, "--body", &pr.body]);
+    for label in &pr.labels {
+        cmd.args(["--add-label", label]);
+    }
+    for reviewer in &pr.reviewers {
+        cmd.args(["--add-reviewer", reviewer]);
+    }
+    for assignee in &pr.assignees {
+        cmd.args(["--add-assignee", assignee]);
+    }
+    if let Some(milestone) = &pr.milestone {
+        cmd.args(["--milestone", milestone]);
+    }
+    cmd
+}
+
+fn unpatch_crates(
+    directories: &[Directory],
+    branch_name: &str,
+    crates: &[Crate],
+    version: Option<&str>,
+    execute: bool,
+    options: RunOptions,
+) -> Result<Vec<RepoReport>> {
+    let results = for_each_repo(directories, options, |dir| {
+        let result = with_clean_tree(&dir.path, options.stash, || {
+            unpatch_crate(dir, branch_name, crates, version, execute)
+        });
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+    let mut successful = vec![];
+    let mut unsuccessful = vec![];
+    let (reports, skipped) = collect_reports(
+        directories,
+        results,
+        Some(branch_name),
+        |dir, result| match result {
+            Err(e) => {
+                let report = RepoReport::failed(dir, &e);
+                unsuccessful.push((dir, e));
+                report
+            }
+            Ok(outcome) => {
+                let report = RepoReport {
+                    crates: outcome.crates.iter().map(CrateReport::from).collect(),
+                    commit: outcome.commit,
+                    pr_url: outcome.pr_url,
+                    ..RepoReport::new(dir)
+                };
+                successful.push((dir, outcome.crates));
+                report
+            }
+        },
+    );
+    if !successful.is_empty() {
+        info!("crates successfully unpatched:");
+        for (cr, removed) in successful {
+            let filename = cr.path.file_name().unwrap().to_string_lossy();
+            info!("\t{filename}");
+            for krate in removed {
+                info!("\t\t`{}`", krate.name);
+            }
+        }
+    }
+
+    if !unsuccessful.is_empty() {
+        info!("crates that could not be unpatched:");
+        for (cr, e) in unsuccessful {
+            let filename = cr.path.file_name().unwrap().to_string_lossy();
+            info!("\t{filename}: {e:#}");
+        }
+    }
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+fn unpatch_crate(
+    directory: &Directory,
+    branch_name: &str,
+    crates: &[Crate],
+    version: Option<&str>,
+    execute: bool,
+) -> Result<BranchOutcome<Crate>> {
+    let repo = &directory.path;
+    let dir_name = repo.file_name().expect("checked");
+    info!("Working with repo {dir_name:?}");
+    let base_branch = base_branch(directory);
+    let remote = directory.remote();
+    let crates = &directory.crates(crates);
+
+    if !branch_exists(repo, branch_name) {
+        create_and_checkout_branch(repo, remote, &base_branch, branch_name)?;
+    } else {
+        info!("Branch '{branch_name}' already exists. Checking it out.");
+        checkout_branch(repo, branch_name)?;
+    }
+
+    let Some(unpatch) = unpatch_manifests(repo, None, crates, version)? else {
+        info!("No patches to remove.");
+        return Ok(BranchOutcome {
+            crates: vec![],
+            commit: Some(head_commit(repo)?),
+            pr_url: None,
+        });
+    };
+    for change in &unpatch.manifests {
+        fs::write(&change.path, &change.new)
+            .with_context(|| format!("Failed to write {}", change.path.display()))?;
+    }
+
+    info!("Running `cargo update`...");
+    cargo_update(repo, &unpatch.removed_crates)?;
+
+    if directory.skip_deny {
+        info!("Skipping deny.toml for this repo.");
+    } else {
+        remove_from_deny_toml(repo, &unpatch.unused_sources)?;
+    }
+
+    let (commit_body, pr_body) = unpatch_messages(&unpatch.removed_crates, version);
+    let commit_message = directory.unpatch_commit_message(
+        branch_name,
+        &unpatch.removed_crates,
+        version,
+        commit_body,
+    );
+    commit_files(
+        repo,
+        &commit_message,
+        &directory.commit,
+        &unpatch.member_manifests(repo),
+    )?;
+
+    let mut pr_url = None;
+    if execute {
+        push_branch(repo, remote, branch_name)?;
+        let pr =
+            directory.unpatch_pull_request(branch_name, &unpatch.removed_crates, version, pr_body);
+        let url = create_pull_request(repo, &base_branch, branch_name, &pr)?;
+        pr_url = Some(url);
+    } else {
+        info!("Dry run complete. Changes were committed but not pushed.");
+    }
+    Ok(BranchOutcome {
+        crates: unpatch.removed_crates,
+        commit: Some(head_commit(repo)?),
+        pr_url,
+    })
+}
+
+/// A file a command changes, with its contents before and after.
+struct FileChange {
+    path: PathBuf,
+    old: String,
+    new: String,
+}
+
+/// The changes `unpatch` makes to the manifests of a repo.
+struct Unpatch {
+    /// The configured crates whose patches are removed.
+    removed_crates: Vec<Crate>,
+    /// Cargo.toml, followed by the workspace members that changed.
+    manifests: Vec<FileChange>,
+    /// Git sources that no remaining patch uses anymore.
+    unused_sources: HashSet<String>,
+}
+
+impl Unpatch {
+    /// The manifests of workspace members that changed, which have to be
+    /// committed along with Cargo.toml.
+    fn member_manifests(&self, repo: &Path) -> Vec<PathBuf> {
+        let cargo_toml_path = repo.join("Cargo.toml");
+        self.manifests
+            .iter()
+            .map(|change| change.path.clone())
+            .filter(|path| *path != cargo_toml_path)
+            .collect()
+    }
+}
+
+/// Computes the manifests of `repo` without the patches for `crates`, and with
+/// the dependencies on them set to `version`. Returns `None` when none of the
+/// crates are patched.
+fn unpatch_manifests(
+    repo: &Path,
+    rev: Option<&str>,
+    crates: &[Crate],
+    version: Option<&str>,
+) -> Result<Option<Unpatch>> {
+    let cargo_toml_path = repo.join("Cargo.toml");
+    let cargo_toml_content = read_repo_file(repo, rev, Path::new("Cargo.toml"))?
+        .with_context(|| "Failed to read Cargo.toml")?;
+    let mut cargo_toml: DocumentMut = cargo_toml_content
+        .parse()
+        .with_context(|| "Failed to parse Cargo.toml")?;
+
+    // Remove the configured crates from [patch.crates-io]
+    let crate_names: HashSet<&str> = crates.iter().map(|c| c.name.as_str()).collect();
+    let removed = remove_patches(&mut cargo_toml, &crate_names);
+    if removed.is_empty() {
+        return Ok(None);
+    }
+    let removed_crates: Vec<Crate> = crates
+        .iter()
+        .filter(|c| removed.contains(&c.name))
+        .cloned()
+        .collect();
+
+    // Set the unpatched dependencies to the released version, in the root
+    // manifest and in every workspace member
+    let mut member_changes = vec![];
+    if let Some(version) = version {
+        set_dependency_versions(&mut cargo_toml, &removed, version);
+        for manifest in workspace_member_manifests(repo, &cargo_toml_content)? {
+            let relative = manifest.strip_prefix(repo).unwrap_or(&manifest);
+            let Some(content) = read_repo_file(repo, rev, relative)
+                .with_context(|| format!("Failed to read {}", manifest.display()))?
+            else {
+                continue;
+            };
+            let mut member: DocumentMut = content
+                .parse()
+                .with_context(|| format!("Failed to parse {}", manifest.display()))?;
+            if set_dependency_versions(&mut member, &removed, version) {
+                member_changes.push(FileChange {
+                    path: manifest,
+                    old: content,
+                    new: member.to_string(),
+                });
+            }
+        }
+    }
+
+    // Git sources that are still used by the remaining patches have to stay
+    // allowed in deny.toml
+    let remaining_sources = patch_git_sources(&cargo_toml);
+    let unused_sources: HashSet<String> = removed_crates
+        .iter()
+        .map(|c| c.repo_url.clone())
+        .filter(|url| !remaining_sources.contains(url))
+        .collect();
+
+    let mut manifests = vec![FileChange {
+        path: cargo_toml_path,
+        old: cargo_toml_content,
+        new: cargo_toml.to_string(),
+    }];
+    manifests.extend(member_changes);
+    Ok(Some(Unpatch {
+        removed_crates,
+        manifests,
+        unused_sources,
+    }))
+}
+
+/// The default commit message body and PR body for removing the patches of
+/// `removed_crates`.
+fn unpatch_messages(removed_crates: &[Crate], version: Option<&str>) -> (String, String) {
+    let crate_list = removed_crates
+        .iter()
+        .map(|c| format!("- `{}`", c.name))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let version_note = version
+        .map(|v| format!(", using version `{v}`"))
+        .unwrap_or_default();
+    let commit_body = format!(
+        "Removes the git patches for the following dependencies{version_note}:\n\n{crate_list}"
+    );
+    let pr_body = format!(
+        "This PR removes the git patches for the following dependencies{version_note}:\n\n{crate_list}"
+    );
+    (commit_body, pr_body)
+}
+
+/// Runs `plan` for every repo, for `--plan`.
+fn plan_repos<F>(
+    directories: &[Directory],
+    branch_name: &str,
+    options: RunOptions,
+    plan: F,
+) -> Result<Vec<RepoReport>>
+where
+    F: Fn(&Directory) -> Result<Vec<CrateReport>> + Sync,
+{
+    let results = for_each_repo(directories, options, |dir| {
+        let result = plan(dir);
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+    let mut failures = vec![];
+    let (reports, skipped) = collect_reports(
+        directories,
+        results,
+        Some(branch_name),
+        |dir, result| match result {
+            Ok(crates) => RepoReport {
+                crates,
+                ..RepoReport::new(dir)
+            },
+            Err(e) => {
+                let report = RepoReport::failed(dir, &e);
+                failures.push((dir_name(dir), e));
+                report
+            }
+        },
+    );
+
+    if !failures.is_empty() {
+        info!("repos that could not be planned:");
+        for (repo, e) in failures {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+/// Works out what `patch` would do in a repo and prints it, without changing
+/// anything. The changes are based on the files on [`plan_revision`], while
+/// the workspace members and Cargo.lock are read from the working tree.
+fn plan_patch(
+    directory: &Directory,
+    branch_name: &str,
+    crates: &[Crate],
+    execute: bool,
+    direct_only: bool,
+) -> Result<Vec<CrateReport>> {
+    let repo = &directory.path;
+    let base_branch = base_branch(directory);
+    let remote = directory.remote();
+    let crates = &directory.crates(crates);
+
+    let mut changes = vec![];
+    let mut commands = create_branch_commands(repo, remote, &base_branch, branch_name);
+
+    let rev = plan_revision(repo, remote, &base_branch, branch_name);
+    let cargo_toml_content = read_repo_file(repo, Some(&rev), Path::new("Cargo.toml"))?
+        .with_context(|| format!("There is no Cargo.toml on `{rev}`"))?;
+    let (cargo_toml, updated_crates) =
+        add_patches(repo, &cargo_toml_content, crates, branch_name, direct_only)?;
+    if !updated_crates.is_empty() {
+        changes.push(FileChange {
+            path: repo.join("Cargo.toml"),
+            old: cargo_toml_content,
+            new: cargo_toml.clone(),
+        });
+        let krates: Vec<Crate> = updated_crates.iter().map(|p| p.krate.clone()).collect();
+        commands.push(cargo_update_command(repo, &krates));
+
+        let deny_toml = read_repo_file(repo, Some(&rev), Path::new("deny.toml"))?;
+        if let Some(deny_toml_content) = deny_toml.filter(|_| !directory.skip_deny) {
+            changes.push(FileChange {
+                new: allow_git_sources(&deny_toml_content, &updated_crates)?,
+                old: deny_toml_content,
+                path: repo.join("deny.toml"),
+            });
+        }
+        commands.extend(commit_commands(
+            repo,
+            &directory.patch_commit_message(branch_name, &updated_crates),
+            &directory.commit,
+            &[],
+        )?);
+    }
+
+    if execute {
+        commands.push(push_command(repo, remote, branch_name));
+        let all_relevant_crates = patched_crates(repo, &cargo_toml, crates)?;
+        let pr = directory.patch_pull_request(branch_name, &all_relevant_crates);
+        commands.push(plan_pull_request_command(
+            repo,
+            &base_branch,
+            branch_name,
+            &pr,
+        )?);
+    }
+
+    print_plan(repo, &changes, &commands);
+    Ok(updated_crates.iter().map(CrateReport::from).collect())
+}
+
+/// Works out what `unpatch` would do in a repo and prints it, without
+/// changing anything. The changes are based on the files on
+/// [`plan_revision`].
+fn plan_unpatch(
+    directory: &Directory,
+    branch_name: &str,
+    crates: &[Crate],
+    version: Option<&str>,
+    execute: bool,
+) -> Result<Vec<CrateReport>> {
+    let repo = &directory.path;
+    let base_branch = base_branch(directory);
+    let remote = directory.remote();
+    let crates = &directory.crates(crates);
+
+    let mut commands = create_branch_commands(repo, remote, &base_branch, branch_name);
+    let rev = plan_revision(repo, remote, &base_branch, branch_name);
+    let Some(unpatch) = unpatch_manifests(repo, Some(&rev), crates, version)? else {
+        print_plan(repo, &[], &commands);
+        return Ok(vec![]);
+    };
+    let member_manifests = unpatch.member_manifests(repo);
+    let mut changes = unpatch.manifests;
+    commands.push(cargo_update_command(repo, &unpatch.removed_crates));
+
+    let deny_toml = read_repo_file(repo, Some(&rev), Path::new("deny.toml"))?;
+    if let Some(deny_toml_content) = deny_toml.filter(|_| !directory.skip_deny) {
+        if let Some(new) = disallow_git_sources(&deny_toml_content, &unpatch.unused_sources)? {
+            changes.push(FileChange {
+                path: repo.join("deny.toml"),
+                old: deny_toml_content,
+                new,
+            });
+        }
+    }
+
+    let (commit_body, pr_body) = unpatch_messages(&unpatch.removed_crates, version);
+    let commit_message = directory.unpatch_commit_message(
+        branch_name,
+        &unpatch.removed_crates,
+        version,
+        commit_body,
+    );
+    commands.extend(commit_commands(
+        repo,
+        &commit_message,
+        &directory.commit,
+        &member_manifests,
+    )?);
+    if execute {
+        commands.push(push_command(repo, remote, branch_name));
+        let pr =
+            directory.unpatch_pull_request(branch_name, &unpatch.removed_crates, version, pr_body);
+        commands.push(plan_pull_request_command(
+            repo,
+            &base_branch,
+            branch_name,
+            &pr,
+        )?);
+    }
+
+    print_plan(repo, &changes, &commands);
+    Ok(unpatch
+        .removed_crates
+        .iter()
+        .map(CrateReport::from)
+        .collect())
+}
+
+/// The revision a real run would make its changes on: the patch branch when
+/// it already exists, otherwise the base branch on the remote as of the last
+/// fetch.
+fn plan_revision(repo: &Path, remote: &str, base_branch: &str, branch_name: &str) -> String {
+    if branch_exists(repo, branch_name) {
+        branch_name.to_string()
+    } else {
+        format!("{remote}/{base_branch}")
+    }
+}
+
+/// Reads `path` in `repo` from `rev`, or from the working tree without one.
+/// Returns `None` when the file does not exist.
+fn read_repo_file(repo: &Path, rev: Option<&str>, path: &Path) -> Result<Option<String>> {
+    match rev {
+        Some(rev) => show_file(repo, rev, &path.to_string_lossy()),
+        None => {
+            let path = repo.join(path);
+            if !path.exists() {
+                return Ok(None);
+            }
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            Ok(Some(content))
+        }
+    }
+}
+
+/// The commands that create `branch_name` from the up to date base branch,
+/// or check it out when it already exists.
+fn create_branch_commands(
+    repo: &Path,
+    remote: &str,
+    base_branch: &str,
+    branch_name: &str,
+) -> Vec<Cmd> {
+    if branch_exists(repo, branch_name) {
+        let mut cmd = Cmd::new("git");
+        cmd.current_dir(repo).args(["checkout", branch_name]);
+        return vec![cmd];
+    }
+    [
+        vec!["checkout", base_branch],
+        vec!["pull", remote, base_branch],
+        vec!["checkout", "-b", branch_name],
+    ]
+    .into_iter()
+    .map(|args| {
+        let mut cmd = Cmd::new("git");
+        cmd.current_dir(repo).args(args);
+        cmd
+    })
+    .collect()
+}
+
+/// The command [`create_pull_request`] would run: `gh pr edit` when a PR is
+/// already open for `branch_name`, otherwise `gh pr create`.
+fn plan_pull_request_command(
+    repo: &Path,
+    base_branch: &str,
+    branch_name: &str,
+    pr: &PullRequest,
+) -> Result<Cmd> {
+    Ok(match find_open_pull_request(repo, branch_name)? {
+        Some(url) => edit_pull_request_command(repo, &url, pr),
+        None => pull_request_command(repo, base_branch, branch_name, pr),
+    })
+}
+
+/// Prints a unified diff of every file that would change, followed by the
+/// commands that would run.
+fn print_plan(repo: &Path, changes: &[FileChange], commands: &[Cmd]) {
+    let dir_name = repo.file_name().expect("checked").to_string_lossy();
+    print_line(format!("Plan for {dir_name}:"));
+    if changes.is_empty() {
+        print_line("No files would change.".to_string());
+    }
+    for change in changes {
+        let path = change.path.strip_prefix(repo).unwrap_or(&change.path);
+        let diff = TextDiff::from_lines(&change.old, &change.new)
+            .unified_diff()
+            .header(
+                &format!("a/{}", path.display()),
+                &format!("b/{}", path.display()),
+            )
+            .to_string();
+        print_line(diff.trim_end().to_string());
+    }
+    if !commands.is_empty() {
+        print_line("Commands that would run:".to_string());
+        for cmd in commands {
+            print_line(format!("\t{}", command_line(cmd)));
+        }
+    }
+}
+
+/// The name of the package a dependency entry refers to, like
+/// [`dependency_package_name`] but for an editable document.
+fn item_package_name<'a>(key: &'a str, dep: &'a Item) -> &'a str {
+    dep.get("package").and_then(Item::as_str).unwrap_or(key)
+}
+
+/// Removes the given crates from `[patch.crates-io]`, along with the table
+/// itself (and `[patch]`) if nothing is left in it. Returns the package names
+/// of the removed patches.
+fn remove_patches(cargo_toml: &mut DocumentMut, crate_names: &HashSet<&str>) -> HashSet<String> {
+    let mut removed = HashSet::new();
+    let Some(patch) = cargo_toml
+        .get_mut("patch")
+        .and_then(Item::as_table_like_mut)
+    else {
+        return removed;
+    };
+    let Some(crates_io) = patch.get_mut("crates-io").and_then(Item::as_table_like_mut) else {
+        return removed;
+    };
+
+    let keys: Vec<(String, String)> = crates_io
+        .iter()
+        .map(|(key, dep)| (key.to_string(), item_package_name(key, dep).to_string()))
+        .filter(|(_, name)| crate_names.contains(name.as_str()))
+        .collect();
+    for (key, name) in keys {
+        crates_io.remove(&key);
+        removed.insert(name);
+    }
+
+    if crates_io.is_empty() {
+        patch.remove("crates-io");
+    }
+    if patch.is_empty() {
+        cargo_toml.remove("patch");
+    }
+    removed
+}
+
+/// Returns the `git` URLs used by the entries in `[patch.crates-io]`.
+fn patch_git_sources(cargo_toml: &DocumentMut) -> HashSet<String> {
+    cargo_toml
+        .get("patch")
+        .and_then(|p| p.get("crates-io"))
+        .and_then(Item::as_table_like)
+        .into_iter()
+        .flat_map(|crates_io| crates_io.iter())
+        .filter_map(|(_, dep)| dep.get("git").and_then(Item::as_str))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Sets every dependency on one of `crate_names` to `version`, in all of the
+/// manifest's dependency tables. Returns whether anything changed.
+fn set_dependency_versions(
+    manifest: &mut DocumentMut,
+    crate_names: &HashSet<String>,
+    version: &str,
+) -> bool {
+    let mut changed = false;
+    for table in DEPENDENCY_TABLES {
+        if let Some(deps) = manifest.get_mut(table).and_then(Item::as_table_like_mut) {
+            changed |= set_versions_in_table(deps, crate_names, version);
+        }
+    }
+    if let Some(targets) = manifest.get_mut("target").and_then(Item::as_table_like_mut) {
+        for (_, target) in targets.iter_mut() {
+            for table in DEPENDENCY_TABLES {
+                if let Some(deps) = target.get_mut(table).and_then(Item::as_table_like_mut) {
+                    changed |= set_versions_in_table(deps, crate_names, version);
+                }
+            }
+        }
+    }
+    if let Some(deps) = manifest
+        .get_mut("workspace")
+        .and_then(|w| w.get_mut("dependencies"))
+        .and_then(Item::as_table_like_mut)
+    {
+        changed |= set_versions_in_table(deps, crate_names, version);
+    }
+    changed
+}
+
+fn set_versions_in_table(
+    deps: &mut dyn TableLike,
+    crate_names: &HashSet<String>,
+    version: &str,
+) -> bool {
+    let mut changed = false;
+    for (key, dep) in deps.iter_mut() {
+        if !crate_names.contains(item_package_name(key.get(), dep)) {
+            continue;
+        }
+        // Either `name = "1.0"` or `name = { version = "1.0", ... }`. Entries
+        // without a version, like `workspace = true`, are left alone.
+        let current = match dep.as_table_like_mut() {
+            Some(table) => table.get_mut("version").and_then(Item::as_value_mut),
+            None => dep.as_value_mut().filter(|v| v.is_str()),
+        };
+        if let Some(current) = current {
+            // Keep any comments or whitespace around the old version
+            let decor = current.decor().clone();
+            *current = version.into();
+            *current.decor_mut() = decor;
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn cleanup_branches(
+    directories: &[Directory],
+    branch_name: &str,
+    comment: Option<&str>,
+    options: RunOptions,
+) -> Result<Vec<RepoReport>> {
+    info!("Cleaning up {branch_name} branches in all directories...");
+    let results = for_each_repo(directories, options, |dir| {
+        let result = cleanup_branch(dir, branch_name, comment);
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+
+    let mut deleted = vec![];
+    let mut already_gone = vec![];
+    let mut failures = vec![];
+    let (reports, skipped) = collect_reports(
+        directories,
+        results,
+        Some(branch_name),
+        |dir, result| match result {
+            Err(e) => {
+                let report = RepoReport::failed(dir, &e);
+                failures.push((dir_name(dir), e));
+                report
+            }
+            Ok(cleanup) => {
+                let report = RepoReport {
+                    pr_url: cleanup.closed_pr.clone(),
+                    ..RepoReport::new(dir)
+                };
+                if cleanup.is_empty() {
+                    already_gone.push(dir_name(dir));
+                } else {
+                    deleted.push((dir_name(dir), cleanup));
+                }
+                report
+            }
+        },
+    );
+
+    if !deleted.is_empty() {
+        info!("repos that were cleaned up:");
+        for (repo, cleanup) in deleted {
+            info!("\t{repo}: {cleanup}");
+        }
+    }
+
+    if !already_gone.is_empty() {
+        info!("repos where `{branch_name}` was already gone:");
+        for repo in already_gone {
+            info!("\t{repo}");
+        }
+    }
+
+    if !failures.is_empty() {
+        info!("repos that could not be cleaned up:");
+        for (repo, e) in failures {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+/// What `cleanup` removed in a repo.
+#[derive(Debug, Default)]
+struct Cleanup {
+    /// URL of the PR that was closed.
+    closed_pr: Option<String>,
+    deleted_local: bool,
+    deleted_remote: bool,
+}
+
+impl Cleanup {
+    fn is_empty(&self) -> bool {
+        self.closed_pr.is_none() && !self.deleted_local && !self.deleted_remote
+    }
+}
+
+impl fmt::Display for Cleanup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(url) = &self.closed_pr {
+            parts.push(format!("closed {url}"));
+        }
+        if self.deleted_local {
+            parts.push("deleted local branch".to_string());
+        }
+        if self.deleted_remote {
+            parts.push("deleted remote branch".to_string());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+fn cleanup_branch(dir: &Directory, branch_name: &str, comment: Option<&str>) -> Result<Cleanup> {
+    let repo = &dir.path;
+    let remote = dir.remote();
+    info!("Cleaning up in {}", repo.display());
+    let mut cleanup = Cleanup::default();
+
+    if let Some(url) = find_open_pull_request(repo, branch_name)? {
+        info!("Closing {url}");
+        let mut cmd = Cmd::new("gh");
+        cmd.current_dir(repo).args(["pr", "close", &url]);
+        if let Some(comment) = comment {
+            cmd.args(["--comment", comment]);
+        }
+        run_command(&mut cmd).with_context(|| format!("Failed to close {url}"))?;
+        cleanup.closed_pr = Some(url);
+    }
+
+    let base_branch = base_branch(dir);
+    run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["checkout", &base_branch]),
+    )
+    .with_context(|| format!("Failed to checkout `{base_branch}` branch"))?;
+
+    if branch_exists(repo, branch_name) {
+        run_command(
+            Cmd::new("git")
+                .current_dir(repo)
+                .args(["branch", "-D", branch_name]),
+        )
+        .with_context(|| format!("Failed to delete local branch `{branch_name}`"))?;
+        cleanup.deleted_local = true;
+    }
+
+    if remote_branch_exists(repo, remote, branch_name)? {
+        run_command(Cmd::new("git").current_dir(repo).args([
+            "push",
+            remote,
+            "--delete",
+            branch_name,
+        ]))
+        .with_context(|| format!("Failed to delete `{remote}/{branch_name}`"))?;
+        cleanup.deleted_remote = true;
+    }
+    Ok(cleanup)
+}
+
+/// Check if the branch exists on the remote
+fn remote_branch_exists(repo: &Path, remote: &str, branch_name: &str) -> Result<bool> {
+    let result = run_command(Cmd::new("git").current_dir(repo).args([
+        "ls-remote",
+        "--exit-code",
+        "--heads",
+        remote,
+        branch_name,
+    ]));
+    match result {
+        Ok(_) => Ok(true),
+        // `--exit-code` exits with 2 when no matching refs are found
+        Err(e)
+            if e.downcast_ref::<CommandError>()
+                .is_some_and(|e| e.status.code() == Some(2)) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to list branches on `{remote}`")),
+    }
+}
+
+/// Returns the URL of the open PR for `branch_name`, if there is one.
+fn find_open_pull_request(repo: &Path, branch_name: &str) -> Result<Option<String>> {
+    let url = run_command(Cmd::new("gh").current_dir(repo).args([
+        "pr",
+        "list",
+        "--head",
+        branch_name,
+        "--state",
+        "open",
+        "--json",
+        "url",
+        "--jq",
+        ".[0].url",
+    ]))
+    .with_context(|| format!("Failed to look up the PR for `{branch_name}`"))?;
+    let url = url.trim();
+    Ok((!url.is_empty()).then(|| url.to_string()))
+}
+
+fn refresh_branches(
+    directories: &[Directory],
+    branch_name: &str,
+    crates: &[Crate],
+    execute: bool,
+    merge: bool,
+    options: RunOptions,
+) -> Result<Vec<RepoReport>> {
+    let results = for_each_repo(directories, options, |dir| {
+        let result = with_clean_tree(&dir.path, options.stash, || {
+            refresh_branch(dir, branch_name, crates, execute, merge)
+        });
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+
+    let mut successful = vec![];
+    let mut unsuccessful = vec![];
+    let (reports, skipped) = collect_reports(
+        directories,
+        results,
+        Some(branch_name),
+        |dir, result| match result {
+            Err(e) => {
+                let report = RepoReport::failed(dir, &e);
+                unsuccessful.push((dir_name(dir), e));
+                report
+            }
+            Ok(outcome) => {
+                let report = RepoReport {
+                    crates: outcome.crates.iter().map(CrateReport::from).collect(),
+                    commit: outcome.commit,
+                    ..RepoReport::new(dir)
+                };
+                successful.push((dir_name(dir), outcome.crates));
+                report
+            }
+        },
+    );
+
+    if !successful.is_empty() {
+        info!("repos successfully refreshed:");
+        for (repo, refreshed) in successful {
+            info!("\t{repo}");
+            for patched_crate in refreshed {
+                info!("\t\t{patched_crate}");
+            }
+        }
+    }
+
+    if !unsuccessful.is_empty() {
+        info!("repos that could not be refreshed:");
+        for (repo, e) in unsuccessful {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+/// Brings the existing patch branch up to date with the base branch and moves
+/// the patched crates to the newest commits of their git refs.
+fn refresh_branch(
+    directory: &Directory,
+    branch_name: &str,
+    crates: &[Crate],
+    execute: bool,
+    merge: bool,
+) -> Result<BranchOutcome<PatchedCrate>> {
+    let repo = &directory.path;
+    let dir_name = repo.file_name().expect("checked");
+    info!("Refreshing repo {dir_name:?}");
+    let base_branch = base_branch(directory);
+    let remote = directory.remote();
+    let crates = &directory.crates(crates);
+
+    if !branch_exists(repo, branch_name) {
+        bail!("Branch `{branch_name}` does not exist, run `patch` first");
+    }
+    checkout_branch(repo, branch_name)?;
+    update_branch(repo, remote, &base_branch, branch_name, merge)?;
+
+    let patched = patched_crates(repo, &read_cargo_toml(repo)?, crates)?;
+    if patched.is_empty() {
+        info!("No crates are patched on `{branch_name}`, nothing to update.");
+    } else {
+        let krates: Vec<Crate> = patched.iter().map(|p| p.krate.clone()).collect();
+        cargo_update(repo, &krates)?;
+        if has_uncommitted_changes(repo, false)? {
+            commit_files(
+                repo,
+                &directory.refresh_commit_message(branch_name, &patched),
+                &directory.commit,
+                &[],
+            )?;
+        } else {
+            info!("The patched crates are already at their newest commits.");
+        }
+    }
+
+    if execute {
+        info!("Force-pushing `{branch_name}` to `{remote}`...");
+        run_command(Cmd::new("git").current_dir(repo).args([
+            "push",
+            "--force-with-lease",
+            remote,
+            branch_name,
+        ]))
+        .with_context(|| format!("Failed to push `{branch_name}` to `{remote}`"))?;
+    } else {
+        info!("Dry run complete. Changes were committed but not pushed.");
+    }
+    Ok(BranchOutcome {
+        crates: patched,
+        commit: Some(head_commit(repo)?),
+        pr_url: None,
+    })
+}
+
+/// Rebases the checked out `branch_name` onto `<remote>/<base_branch>`, or
+/// merges it in when `merge` is true.
+///
+/// Conflicts in Cargo.lock are resolved by taking the lock file of the base
+/// branch, since `refresh` runs `cargo update` for the patched crates right
+/// after. Any other conflict aborts the rebase or merge, leaving the branch as
+/// it was.
+fn update_branch(
+    repo: &Path,
+    remote: &str,
+    base_branch: &str,
+    branch_name: &str,
+    merge: bool,
+) -> Result<()> {
+    info!("Fetching latest changes from `{remote}/{base_branch}`...");
+    run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["fetch", remote, base_branch]),
+    )
+    .with_context(|| format!("Failed to fetch `{remote}/{base_branch}`"))?;
+
+    let upstream = format!("{remote}/{base_branch}");
+    let (action, args, description) = if merge {
+        (
+            "merge",
+            vec!["merge", "--no-edit", &upstream],
+            format!("merge `{upstream}` into `{branch_name}`"),
+        )
+    } else {
+        (
+            "rebase",
+            vec!["rebase", &upstream],
+            format!("rebase `{branch_name}` onto `{upstream}`"),
+        )
+    };
+    info!("Running `git {action}` to {description}...");
+    let mut result = run_command(Cmd::new("git").current_dir(repo).args(args));
+    while result.is_err() {
+        let lock_files = match lock_file_conflicts(repo) {
+            Ok(lock_files) if !lock_files.is_empty() => lock_files,
+            _ => break,
+        };
+        info!("Taking Cargo.lock from `{upstream}` to resolve its conflicts");
+        if let Err(e) = take_lock_files(repo, &upstream, &lock_files) {
+            result = Err(e);
+            break;
+        }
+        result = continue_rebase_or_merge(repo, merge);
+    }
+    if let Err(e) = result {
+        if let Err(abort_error) =
+            run_command(Cmd::new("git").current_dir(repo).args([action, "--abort"]))
+        {
+            error!("{abort_error:#}");
+        }
+        return Err(e).with_context(|| format!("Failed to {description}"));
+    }
+    Ok(())
+}
+
+/// The conflicted files of a stopped rebase or merge, when they are all lock
+/// files. Empty when there are none, or when other files conflict too.
+fn lock_file_conflicts(repo: &Path) -> Result<Vec<String>> {
+    let output = run_command(Cmd::new("git").current_dir(repo).args([
+        "diff",
+        "--name-only",
+        "--diff-filter=U",
+    ]))
+    .with_context(|| "Failed to list the conflicted files")?;
+    let files: Vec<String> = output.lines().map(str::to_string).collect();
+    if files
+        .iter()
+        .all(|file| Path::new(file).file_name() == Some("Cargo.lock".as_ref()))
+    {
+        Ok(files)
+    } else {
+        Ok(vec![])
+    }
+}
+
+/// Resolves the conflicts in `lock_files` with their version on `upstream`.
+fn take_lock_files(repo: &Path, upstream: &str, lock_files: &[String]) -> Result<()> {
+    run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["checkout", upstream, "--"])
+            .args(lock_files),
+    )
+    .with_context(|| format!("Failed to take Cargo.lock from `{upstream}`"))?;
+    Ok(())
+}
+
+/// Continues a rebase or merge once its conflicts are resolved, skipping a
+/// commit of the rebase that has nothing left to change.
+fn continue_rebase_or_merge(repo: &Path, merge: bool) -> Result<String> {
+    let args = if merge {
+        ["commit", "--no-edit"]
+    } else {
+        let nothing_staged = Cmd::new("git")
+            .current_dir(repo)
+            .args(["diff", "--cached", "--quiet"])
+            .status()
+            .is_ok_and(|status| status.success());
+        if nothing_staged {
+            ["rebase", "--skip"]
+        } else {
+            ["rebase", "--continue"]
+        }
+    };
+    run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .env("GIT_EDITOR", "true")
+            .args(args),
+    )
+}
+
+/// Opens or updates the tracking issue with a checklist of the PRs in
+/// `reports`, and links each PR back to it. Returns the URL of the issue, or
+/// `None` when there are no PRs to track.
+fn update_tracking_issue(
+    config: &TrackingIssueConfig,
+    branch_name: &str,
+    reports: &[RepoReport],
+) -> Result<Option<String>> {
+    let mut pr_urls: Vec<&str> = vec![];
+    for url in reports.iter().filter_map(|report| report.pr_url.as_deref()) {
+        if !pr_urls.contains(&url) {
+            pr_urls.push(url);
+        }
+    }
+    if pr_urls.is_empty() {
+        info!("No pull requests to track.");
+        return Ok(None);
+    }
+    let title = config
+        .title
+        .as_deref()
+        .unwrap_or("Release `{branch}`")
+        .replace("{branch}", branch_name);
+
+    let existing = find_open_issue(&config.repo, &title)?;
+    let previous_body = existing.as_ref().map_or("", |issue| issue.body.as_str());
+    let body = tracking_issue_body(branch_name, &pr_urls, previous_body);
+    let url = match existing {
+        Some(issue) if body == issue.body => {
+            info!("Tracking issue already lists every PR: {}", issue.url);
+            issue.url
+        }
+        Some(issue) => {
+            run_command(Cmd::new("gh").args(["issue", "edit", &issue.url, "--body", &body]))
+                .with_context(|| format!("Failed to update {}", issue.url))?;
+            info!("Tracking issue updated: {}", issue.url);
+            issue.url
+        }
+        None => {
+            let mut cmd = Cmd::new("gh");
+            cmd.args([
+                "issue",
+                "create",
+                "--repo",
+                &config.repo,
+                "--title",
+                &title,
+                "--body",
+                &body,
+            ]);
+            for label in &config.labels {
+                cmd.args(["--label", label]);
+            }
+            let output = run_command(&mut cmd)
+                .with_context(|| format!("Failed to open the tracking issue in {}", config.repo))?;
+            // `gh issue create` prints the URL of the new issue
+            let url = output.trim().to_string();
+            info!("Tracking issue created: {url}");
+            url
+        }
+    };
+
+    for pr_url in pr_urls {
+        link_pull_request(pr_url, &url)?;
+    }
+    Ok(Some(url))
+}
+
+/// An issue, as listed by `gh issue list --json url,title,body`.
+#[derive(Debug, Deserialize)]
+struct Issue {
+    url: String,
+    title: String,
+    body: String,
+}
+
+/// Returns the open issue in `repo` titled exactly `title`, if there is one.
+fn find_open_issue(repo: &str, title: &str) -> Result<Option<Issue>> {
+    let output = run_command(Cmd::new("gh").args([
+        "issue",
+        "list",
+        "--repo",
+        repo,
+        "--state",
+        "open",
+        "--search",
+        &format!("{title} in:title"),
+        "--json",
+        "url,title,body",
+    ]))
+    .with_context(|| format!("Failed to look up the tracking issue in {repo}"))?;
+    let issues: Vec<Issue> =
+        serde_json::from_str(&output).with_context(|| "Failed to parse `gh issue list` output")?;
+    Ok(issues.into_iter().find(|issue| issue.title == title))
+}
+
+/// The body of the tracking issue with a checklist item for each of
+/// `pr_urls`.
+///
+/// An existing `previous_body` is kept as it is, including the PRs it already
+/// lists and whether they are checked off, and new PRs are added after its
+/// last checklist item.
+fn tracking_issue_body(branch_name: &str, pr_urls: &[&str], previous_body: &str) -> String {
+    if previous_body.trim().is_empty() {
+        let checklist = pr_urls
+            .iter()
+            .map(|url| format!("- [ ] {url}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        return format!("Pull requests for the `{branch_name}` release:\n\n{checklist}");
+    }
+
+    let mut lines: Vec<String> = previous_body.lines().map(str::to_string).collect();
+    let listed: HashSet<&str> = previous_body.lines().filter_map(checklist_url).collect();
+    let new_items: Vec<String> = pr_urls
+        .iter()
+        .filter(|url| !listed.contains(*url))
+        .map(|url| format!("- [ ] {url}"))
+        .collect();
+    if new_items.is_empty() {
+        return previous_body.to_string();
+    }
+    let end_of_checklist = lines
+        .iter()
+        .rposition(|line| checklist_url(line).is_some())
+        .map_or(lines.len(), |i| i + 1);
+    lines.splice(end_of_checklist..end_of_checklist, new_items);
+    lines.join("\n")
+}
+
+/// The URL a checklist item like `- [x] <url>` points to.
+fn checklist_url(line: &str) -> Option<&str> {
+    let line = line.trim();
+    ["- [ ] ", "- [x] ", "- [X] "]
+        .iter()
+        .find_map(|prefix| line.strip_prefix(prefix))
+        .map(str::trim)
+}
+
+/// Adds a link to the tracking issue at `issue_url` to the body of the PR at
+/// `pr_url`, unless it already has one.
+fn link_pull_request(pr_url: &str, issue_url: &str) -> Result<()> {
+    let body =
+        run_command(Cmd::new("gh").args(["pr", "view", pr_url, "--json", "body", "--jq", ".body"]))
+            .with_context(|| format!("Failed to read the body of {pr_url}"))?;
+    if body.contains(issue_url) {
+        return Ok(());
+    }
+    let body = format!("{}\n\nTracking issue: {issue_url}", body.trim_end());
+    run_command(Cmd::new("gh").args(["pr", "edit", pr_url, "--body", &body]))
+        .with_context(|| format!("Failed to link {pr_url} to {issue_url}"))?;
+    Ok(())
+}
+
+/// The step of `update` that failed for a repo.
+enum UpdateFailure {
+    Checkout(anyhow::Error),
+    Update(anyhow::Error),
+    Check(anyhow::Error),
+}
+
+fn update_and_check(
+    directories: &[Directory],
+    crates: &[Crate],
+    options: RunOptions,
+) -> Result<Vec<RepoReport>> {
+    info!("");
+    let results = for_each_repo(directories, options, |dir| {
+        update_and_check_repo(dir, crates, options.stash)
+    });
+    let mut successes = vec![];
+    let mut main_failures = vec![];
+    let mut update_failures = vec![];
+    let mut check_failures = vec![];
+    let (reports, skipped) = collect_reports(directories, results, None, |dir, result| {
+        let mut report = match &result {
+            Ok(diagnostics) => RepoReport {
+                check: Some(CheckReport {
+                    success: true,
+                    diagnostics: diagnostics.clone(),
+                }),
+                ..RepoReport::new(dir)
+            },
+            Err(UpdateFailure::Check(e)) => RepoReport {
+                check: Some(CheckReport {
+                    success: false,
+                    diagnostics: e
+                        .downcast_ref::<CommandError>()
+                        .map(|e| check_diagnostics(&e.stderr))
+                        .unwrap_or_default(),
+                }),
+                ..RepoReport::failed(dir, e)
+            },
+            Err(UpdateFailure::Checkout(e) | UpdateFailure::Update(e)) => {
+                RepoReport::failed(dir, e)
+            }
+        };
+        report.branch = Some(base_branch(dir));
+        if let Ok(commit) = head_commit(&dir.path) {
+            report.commit = Some(commit);
+        }
+        let dir_name = dir_name(dir);
+        match result {
+            Ok(_) => successes.push(dir_name),
+            Err(UpdateFailure::Checkout(e)) => main_failures.push((dir_name, e)),
+            Err(UpdateFailure::Update(e)) => update_failures.push((dir_name, e)),
+            Err(UpdateFailure::Check(e)) => check_failures.push((dir_name, e)),
+        }
+        report
+    });
+
+    if !successes.is_empty() {
+        info!("repos successfully updated and checked:");
+        for repo in successes {
+            info!("\t{repo}");
+        }
+    }
+
+    if !main_failures.is_empty() {
+        info!("repos that could not checkout their base branch:");
+        for (repo, e) in main_failures {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+
+    if !update_failures.is_empty() {
+        info!("repos that did not run `cargo update` successfully:");
+        for (repo, e) in update_failures {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+
+    if !check_failures.is_empty() {
+        info!("repos that had an error in `cargo check` or their check commands:");
+        for (repo, e) in check_failures {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+/// Returns the diagnostics of `cargo check` when everything succeeded.
+fn update_and_check_repo(
+    dir: &Directory,
+    crates: &[Crate],
+    stash: bool,
+) -> Result<Vec<String>, UpdateFailure> {
+    let dir_name = dir
+        .path
+        .file_name()
+        .expect("checked")
+        .to_str()
+        .expect("checked");
+    let base_branch = base_branch(dir);
+    print_line(format!(
+        "Updating and checking {dir_name} on `{base_branch}` branch"
+    ));
+    let stashed = match stash_changes(&dir.path, stash) {
+        Err(e) => {
+            error!("{e:?}");
+            return Err(UpdateFailure::Checkout(e));
+        }
+        Ok(stashed) => stashed,
+    };
+    let result = update_and_check_base_branch(dir, crates, &base_branch);
+    if let Some(stashed) = stashed {
+        if let Err(e) =
+            discard_lock_file(&dir.path).and_then(|()| restore_changes(&dir.path, stashed))
+        {
+            error!("{e:?}");
+            if result.is_ok() {
+                return Err(UpdateFailure::Checkout(e));
+            }
+        }
+    }
+    result
+}
+
+/// Throws away the Cargo.lock that `cargo update` wrote, so the stashed
+/// changes can be restored on a clean tree.
+fn discard_lock_file(repo: &Path) -> Result<()> {
+    let tracked = run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["ls-files", "Cargo.lock"]),
+    )
+    .with_context(|| "Failed to check whether Cargo.lock is tracked")?;
+    if !tracked.trim().is_empty() {
+        run_command(
+            Cmd::new("git")
+                .current_dir(repo)
+                .args(["checkout", "--", "Cargo.lock"]),
+        )
+        .with_context(|| "Failed to discard the changes to Cargo.lock")?;
+    } else if repo.join("Cargo.lock").exists() {
+        fs::remove_file(repo.join("Cargo.lock")).with_context(|| "Failed to remove Cargo.lock")?;
+    }
+    Ok(())
+}
+
+fn update_and_check_base_branch(
+    dir: &Directory,
+    crates: &[Crate],
+    base_branch: &str,
+) -> Result<Vec<String>, UpdateFailure> {
+    let dir_name = dir
+        .path
+        .file_name()
+        .expect("checked")
+        .to_str()
+        .expect("checked");
+    let repo = &dir.path;
+    if let Err(e) = checkout_and_pull(repo, dir.remote(), base_branch) {
+        error!("{e:?}");
+        return Err(UpdateFailure::Checkout(e));
+    };
+    let referenced_crates = match list_relevant_crates(repo, &dir.crates(crates)) {
+        Err(e) => {
+            error!("{e:?}");
+            return Err(UpdateFailure::Update(e));
+        }
+        Ok(r) => r,
+    };
+    if let Err(e) = cargo_update(repo, &referenced_crates) {
+        error!("Unable to run `cargo update` on {dir_name}: {e:?}");
+        return Err(UpdateFailure::Update(e));
+    }
+    let diagnostics = match cargo_check(repo) {
+        Err(e) => {
+            error!("Error running `cargo check` for {dir_name}: {e:?}");
+            return Err(UpdateFailure::Check(e));
+        }
+        Ok(diagnostics) => diagnostics,
+    };
+    if let Err(e) = run_check_commands(repo, &dir.check_commands) {
+        error!("Error running check commands for {dir_name}: {e:?}");
+        return Err(UpdateFailure::Check(e));
+    }
+    Ok(diagnostics)
+}
+
+fn list_relevant_crates(repo: &Path, crates: &[Crate]) -> Result<Vec<Crate>> {
+    let cargo_toml_content =
+        fs::read_to_string(repo.join("Cargo.toml")).with_context(|| "Failed to read Cargo.toml")?;
+
+    // Parse Cargo.toml and any workspace members to find referenced dependencies
+    let referenced_crates = parse_workspace_referenced_crates(repo, &cargo_toml_content)?;
+    let mut relevant_crates = vec![];
+    for krate in crates {
+        if referenced_crates.contains(&krate.name) {
+            relevant_crates.push(krate.clone());
+        }
+    }
+    Ok(relevant_crates)
+}
+
+/// Runs `cargo check`, returning its warnings.
+fn cargo_check(repo: &Path) -> Result<Vec<String>> {
+    let (_stdout, stderr) = run_command_output(Cmd::new("cargo").current_dir(repo).args([
+        "check",
+        "--all-targets",
+        "--all-features",
+        "--message-format",
+        "short",
+    ]))
+    .with_context(|| "`cargo check` failed with errors")?;
+    Ok(check_diagnostics(&stderr))
+}
+
+/// The diagnostics in the stderr of a check, leaving out cargo's progress
+/// lines, which are indented.
+fn check_diagnostics(stderr: &str) -> Vec<String> {
+    stderr
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with(char::is_whitespace))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Runs each of the directory's extra check commands through `sh -c`.
+fn run_check_commands(repo: &Path, check_commands: &[String]) -> Result<()> {
+    for command in check_commands {
+        info!("Running `{command}`...");
+        run_command(Cmd::new("sh").current_dir(repo).args(["-c", command]))
+            .with_context(|| format!("`{command}` failed with errors"))?;
+    }
+    Ok(())
+}
+
+fn checkout_and_pull(repo: &Path, remote: &str, base_branch: &str) -> Result<()> {
+    info!("Checking out `{base_branch}`");
+    // Checkout the base branch
+    checkout_branch(repo, base_branch)?;
+    // Pull the latest changes from `<remote>/<base_branch>`
+    info!("Pulling latest changes from `{remote}/{base_branch}`...");
+    run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["pull", remote, base_branch]),
+    )
+    .with_context(|| format!("Failed to pull from `{remote}/{base_branch}`"))?;
+    Ok(())
+}
+
+fn reset(directories: &[Directory], force: bool, options: RunOptions) -> Result<Vec<RepoReport>> {
+    let results = for_each_repo(directories, options, |dir| {
+        let dir_name = dir.path.file_name().expect("checked").to_string_lossy();
+        print_line(format!("Reseting {dir_name}"));
+        let result = reset_repo(&dir.path, force);
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+    let mut failures = vec![];
+    let mut successes = vec![];
+    let (reports, skipped) =
+        collect_reports(directories, results, None, |dir, result| match result {
+            Err(e) => {
+                let report = RepoReport::failed(dir, &e);
+                failures.push((dir_name(dir), e));
+                report
+            }
+            Ok(_) => {
+                successes.push(dir_name(dir));
+                RepoReport::new(dir)
+            }
+        });
+
+    if !successes.is_empty() {
+        info!("repos successfully reset:");
+        for repo in successes {
+            info!("\t{repo}");
+        }
+    }
+
+    if !failures.is_empty() {
+        info!("repos that could not reset:");
+        for (repo, e) in failures {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+fn reset_repo(repo: &Path, force: bool) -> Result<String> {
+    if !force && has_uncommitted_changes(repo, false)? {
+        bail!(
+            "Skipping {}: resetting would discard its uncommitted changes, rerun with `--force`",
+            repo.display()
+        );
+    }
+    run_command(Cmd::new("git").current_dir(repo).args(["reset", "--hard"]))
+        .with_context(|| "Failed to run `git reset --hard`")
+}
+
+/// The release state of a repo, shown by `status`.
+#[derive(Debug, Serialize)]
+struct RepoStatus {
+    /// The branch that is checked out, `HEAD` when detached.
+    current_branch: String,
+    /// Whether there are uncommitted changes.
+    dirty: bool,
+    /// Number of commits the current branch is ahead of its remote branch,
+    /// if the remote has it.
+    ahead: Option<usize>,
+    /// Number of commits the current branch is behind its remote branch, if
+    /// the remote has it.
+    behind: Option<usize>,
+    patch_branch_local: bool,
+    /// Whether the remote has the patch branch, `None` when the remote could
+    /// not be reached.
+    patch_branch_remote: Option<bool>,
+    /// The configured crates that are patched in `[patch.crates-io]` on the
+    /// patch branch, or on the current branch when there is no patch branch.
+    patches: Vec<PatchStatus>,
+    /// The PR for the patch branch.
+    pr: Option<PrStatus>,
+    /// Why the PR could not be looked up, like `gh` not being logged in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pr_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PatchStatus {
+    name: String,
+    git_ref: Option<GitRef>,
+    transitive: bool,
+    /// Whether deny.toml allows the git source of the crate, `None` when the
+    /// repo has no deny.toml.
+    deny_allowed: Option<bool>,
+}
+
+/// The fields of `gh pr view --json` we ask for.
+#[derive(Debug, Deserialize, Serialize)]
+struct PrStatus {
+    url: String,
+    /// `OPEN`, `CLOSED` or `MERGED`.
+    state: String,
+    #[serde(rename(deserialize = "isDraft"))]
+    is_draft: bool,
+    /// `APPROVED`, `CHANGES_REQUESTED`, `REVIEW_REQUIRED`, or empty.
+    #[serde(rename(deserialize = "reviewDecision"))]
+    review_decision: String,
+}
+
+fn status(
+    directories: &[Directory],
+    branch_name: &str,
+    crates: &[Crate],
+    options: RunOptions,
+) -> Result<Vec<RepoReport>> {
+    let results = for_each_repo(directories, options, |dir| {
+        let result = repo_status(dir, branch_name, crates);
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+
+    let mut rows = vec![[
+        "REPO",
+        "BRANCH",
+        "TREE",
+        "REMOTE",
+        "PATCH BRANCH",
+        "PATCHES",
+        "DENY",
+        "PR",
+    ]
+    .map(String::from)];
+    let mut failures = vec![];
+    let (reports, skipped) = collect_reports(
+        directories,
+        results,
+        Some(branch_name),
+        |dir, result| match result {
+            Err(e) => {
+                let report = RepoReport::failed(dir, &e);
+                failures.push((dir_name(dir), e));
+                report
+            }
+            Ok(status) => {
+                rows.push(status_row(&dir_name(dir), &status));
+                RepoReport {
+                    pr_url: status.pr.as_ref().map(|pr| pr.url.clone()),
+                    status: Some(status),
+                    ..RepoReport::new(dir)
+                }
+            }
+        },
+    );
+
+    let mut widths = [0; 8];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        print_line(line.trim_end().to_string());
+    }
+
+    if !failures.is_empty() {
+        info!("repos whose status could not be read:");
+        for (repo, e) in failures {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+/// Renders the status of a repo as a row of the `status` table.
+fn status_row(dir_name: &str, status: &RepoStatus) -> [String; 8] {
+    let tree = if status.dirty { "dirty" } else { "clean" };
+    let remote = match (status.ahead, status.behind) {
+        (Some(ahead), Some(behind)) => format!("+{ahead} -{behind}"),
+        _ => "-".to_string(),
+    };
+    let mut patch_branch = vec![];
+    if status.patch_branch_local {
+        patch_branch.push("local");
+    }
+    match status.patch_branch_remote {
+        Some(true) => patch_branch.push("remote"),
+        Some(false) => {}
+        None => patch_branch.push("remote unknown"),
+    }
+    let patch_branch = if patch_branch.is_empty() {
+        "-".to_string()
+    } else {
+        patch_branch.join(", ")
+    };
+    let patches = status
+        .patches
+        .iter()
+        .map(|patch| match &patch.git_ref {
+            Some(git_ref) => format!("{}@{}", patch.name, git_ref.value()),
+            None => patch.name.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let not_allowed: Vec<&str> = status
+        .patches
+        .iter()
+        .filter(|patch| patch.deny_allowed == Some(false))
+        .map(|patch| patch.name.as_str())
+        .collect();
+    let deny = if status.patches.iter().all(|p| p.deny_allowed.is_none()) {
+        "-".to_string()
+    } else if not_allowed.is_empty() {
+        "ok".to_string()
+    } else {
+        format!("missing {}", not_allowed.join(", "))
+    };
+    let pr = match &status.pr {
+        Some(pr) if pr.is_draft => format!("DRAFT {}", pr.url),
+        Some(pr) => format!("{} {}", pr.state, pr.url),
+        None if status.pr_error.is_some() => "unknown".to_string(),
+        None => "-".to_string(),
+    };
+    [
+        dir_name.to_string(),
+        status.current_branch.clone(),
+        tree.to_string(),
+        remote,
+        patch_branch.to_string(),
+        if patches.is_empty() {
+            "-".to_string()
+        } else {
+            patches
+        },
+        deny,
+        pr,
+    ]
+}
+
+fn repo_status(directory: &Directory, branch_name: &str, crates: &[Crate]) -> Result<RepoStatus> {
+    let repo = &directory.path;
+    let remote = directory.remote();
+
+    let current_branch = current_branch(repo)?;
+    let dirty = has_uncommitted_changes(repo, true)?;
+
+    // Compare against the remote branch as of the last fetch
+    let remote_ref = format!("refs/remotes/{remote}/{current_branch}");
+    let (ahead, behind) = if branch_exists(repo, &remote_ref) {
+        let output = run_command(Cmd::new("git").current_dir(repo).args([
+            "rev-list",
+            "--left-right",
+            "--count",
+            &format!("HEAD...{remote_ref}"),
+        ]))
+        .with_context(|| format!("Failed to compare with `{remote}/{current_branch}`"))?;
+        let mut counts = output.split_whitespace().map(str::parse::<usize>);
+        match (counts.next(), counts.next()) {
+            (Some(Ok(ahead)), Some(Ok(behind))) => (Some(ahead), Some(behind)),
+            _ => bail!("Unexpected output of `git rev-list --count`: {output:?}"),
+        }
+    } else {
+        (None, None)
+    };
+
+    let patch_branch_local = branch_exists(repo, &format!("refs/heads/{branch_name}"));
+    let patch_branch_remote = match remote_branch_exists(repo, remote, branch_name) {
+        Ok(exists) => Some(exists),
+        Err(e) => {
+            warn!("{e:#}");
+            None
+        }
+    };
+
+    // Read the patches from the patch branch, falling back to the remote
+    // branch as of the last fetch, and to the working tree without either
+    let remote_patch_branch = format!("refs/remotes/{remote}/{branch_name}");
+    let patch_branch = if patch_branch_local {
+        Some(branch_name)
+    } else if branch_exists(repo, &remote_patch_branch) {
+        Some(remote_patch_branch.as_str())
+    } else {
+        None
+    };
+    let (cargo_toml_content, deny_toml_content) = match patch_branch {
+        Some(rev) => (
+            show_file(repo, rev, "Cargo.toml")?
+                .with_context(|| format!("There is no Cargo.toml on `{rev}`"))?,
+            show_file(repo, rev, "deny.toml")?,
+        ),
+        None => {
+            let deny_toml_path = repo.join("deny.toml");
+            let deny_toml_content = if deny_toml_path.exists() {
+                Some(
+                    fs::read_to_string(&deny_toml_path)
+                        .with_context(|| "Failed to read deny.toml")?,
+                )
+            } else {
+                None
+            };
+            (read_cargo_toml(repo)?, deny_toml_content)
+        }
+    };
+    let existing_patches = parse_existing_patches(&cargo_toml_content)?;
+    let referenced_crates = parse_workspace_referenced_crates(repo, &cargo_toml_content)?;
+    let allowed_git = deny_toml_content
+        .as_deref()
+        .map(deny_allowed_git)
+        .transpose()?;
+    let patches = directory
+        .crates(crates)
+        .into_iter()
+        .filter_map(|krate| {
+            let git_ref = existing_patches.get(&krate.name)?;
+            Some(PatchStatus {
+                git_ref: git_ref.clone(),
+                transitive: !referenced_crates.contains(&krate.name),
+                deny_allowed: allowed_git
+                    .as_ref()
+                    .map(|allowed| allowed.contains(&krate.repo_url)),
+                name: krate.name,
+            })
+        })
+        .collect();
+
+    let (pr, pr_error) = match pull_request_status(repo, branch_name) {
+        Ok(pr) => (pr, None),
+        Err(e) => {
+            warn!("{e:#}");
+            (None, Some(format!("{e:#}")))
+        }
+    };
+
+    Ok(RepoStatus {
+        current_branch,
+        dirty,
+        ahead,
+        behind,
+        patch_branch_local,
+        patch_branch_remote,
+        patches,
+        pr,
+        pr_error,
+    })
+}
+
+/// The git sources in `sources.allow-git` of deny.toml.
+fn deny_allowed_git(deny_toml_content: &str) -> Result<HashSet<String>> {
+    let deny_toml: toml::Value =
+        toml::from_str(deny_toml_content).with_context(|| "Failed to parse deny.toml")?;
+    let allowed = deny_toml
+        .get("sources")
+        .and_then(|sources| sources.get("allow-git"))
+        .and_then(|allow_git| allow_git.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|repo| repo.as_str().map(str::to_string))
+        .collect();
+    Ok(allowed)
+}
+
+/// Returns the content of `path` at `rev`, or `None` when it does not exist
+/// there.
+fn show_file(repo: &Path, rev: &str, path: &str) -> Result<Option<String>> {
+    let result = run_command(
+        Cmd::new("git")
+            .current_dir(repo)
+            .args(["show", &format!("{rev}:{path}")]),
+    );
+    match result {
+        Ok(content) => Ok(Some(content)),
+        Err(e)
+            if e.downcast_ref::<CommandError>().is_some_and(|e| {
+                e.stderr.contains("does not exist") || e.stderr.contains("exists on disk")
+            }) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {path} on `{rev}`")),
+    }
+}
+
+/// Returns the state of the most recent PR for `branch_name`, if there is one.
+fn pull_request_status(repo: &Path, branch_name: &str) -> Result<Option<PrStatus>> {
+    let result = run_command(Cmd::new("gh").current_dir(repo).args([
+        "pr",
+        "view",
+        branch_name,
+        "--json",
+        "url,state,isDraft,reviewDecision",
+    ]));
+    match result {
+        Ok(json) => {
+            let pr = serde_json::from_str(&json)
+                .with_context(|| "Failed to parse the output of `gh pr view`")?;
+            Ok(Some(pr))
+        }
+        Err(e)
+            if e.downcast_ref::<CommandError>()
+                .is_some_and(|e| e.stderr.contains("no pull requests found")) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to look up the PR for `{branch_name}`")),
+    }
+}
+
+fn wait_for_ci(
+    directories: &[Directory],
+    branch_name: &str,
+    timeout: Duration,
+    interval: Duration,
+    options: RunOptions,
+) -> Result<Vec<RepoReport>> {
+    info!("Waiting for the checks on the `{branch_name}` PRs...");
+    let deadline = Instant::now() + timeout;
+    let results = for_each_repo(directories, options, |dir| {
+        let result = wait_for_checks(dir, branch_name, deadline, interval);
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+
+    let mut passed = vec![];
+    let mut failed = vec![];
+    let mut timed_out = vec![];
+    let mut failures = vec![];
+    let (reports, skipped) =
+        collect_reports(directories, results, Some(branch_name), |dir, result| {
+            let (url, ci) = match result {
+                Err(e) => {
+                    let report = RepoReport::failed(dir, &e);
+                    failures.push((dir_name(dir), e));
+                    return report;
+                }
+                Ok(result) => result,
+            };
+            let failed_checks = ci.failed().count();
+            let error = if failed_checks > 0 {
+                Some(format!(
+                    "{failed_checks} of {} checks failed",
+                    ci.checks.len()
+                ))
+            } else if ci.timed_out {
+                Some("timed out waiting for the checks to finish".to_string())
+            } else {
+                None
+            };
+            let report = RepoReport {
+                pr_url: Some(url.clone()),
+                ci: Some(ci.clone()),
+                error,
+                ..RepoReport::new(dir)
+            };
+            if failed_checks > 0 {
+                failed.push((dir_name(dir), url, ci));
+            } else if ci.timed_out {
+                timed_out.push((dir_name(dir), url, ci));
+            } else {
+                passed.push((dir_name(dir), url));
+            }
+            report
+        });
+
+    if !passed.is_empty() {
+        info!("repos where all checks passed:");
+        for (repo, url) in passed {
+            info!("\t{repo}: {url}");
+        }
+    }
+
+    if !failed.is_empty() {
+        info!("repos with failed checks:");
+        for (repo, url, ci) in failed {
+            info!("\t{repo}: {url}");
+            for check in ci.failed() {
+                info!("\t\t{}: {}", check.name, check.link);
+            }
+        }
+    }
+
+    if !timed_out.is_empty() {
+        info!("repos where the checks did not finish in time:");
+        for (repo, url, ci) in timed_out {
+            info!("\t{repo}: {url}");
+            for check in ci.checks.iter().filter(|c| c.bucket == "pending") {
+                info!("\t\t{}: {}", check.name, check.link);
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        info!("repos whose checks could not be read:");
+        for (repo, e) in failures {
+            info!("\t{repo}: {e:#}");
+        }
+    }
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+/// The checks of a PR, from `ci`.
+#[derive(Debug, Clone, Serialize)]
+struct CiReport {
+    /// Whether some checks were still pending when `ci` stopped waiting.
+    timed_out: bool,
+    checks: Vec<PrCheck>,
+}
+
+impl CiReport {
+    fn failed(&self) -> impl Iterator<Item = &PrCheck> {
+        self.checks
+            .iter()
+            .filter(|c| c.bucket == "fail" || c.bucket == "cancel")
+    }
+}
+
+/// A check on a PR, as listed by `gh pr checks --json name,state,bucket,link`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct PrCheck {
+    name: String,
+    state: String,
+    /// `pass`, `fail`, `pending`, `skipping` or `cancel`.
+    bucket: String,
+    link: String,
+}
+
+/// Polls the checks of the open PR for `branch_name` every `interval` until
+/// none are pending, or `deadline` passes. Returns the URL of the PR and its
+/// checks.
+fn wait_for_checks(
+    directory: &Directory,
+    branch_name: &str,
+    deadline: Instant,
+    interval: Duration,
+) -> Result<(String, CiReport)> {
+    let repo = &directory.path;
+    let Some(url) = find_open_pull_request(repo, branch_name)? else {
+        bail!("There is no open PR for `{branch_name}`");
+    };
+    info!("Waiting for the checks on {url}");
+    loop {
+        let checks = pull_request_checks(repo, &url)?;
+        let pending = checks.iter().filter(|c| c.bucket == "pending").count();
+        let timed_out = pending > 0 && Instant::now() + interval > deadline;
+        if pending == 0 || timed_out {
+            return Ok((url, CiReport { timed_out, checks }));
+        }
+        info!("{pending} of {} checks on {url} are pending", checks.len());
+        std::thread::sleep(interval);
+    }
+}
+
+/// The checks of the PR at `url`. A PR without any checks has none pending,
+/// so it counts as finished.
+fn pull_request_checks(repo: &Path, url: &str) -> Result<Vec<PrCheck>> {
+    let result = run_command(Cmd::new("gh").current_dir(repo).args([
+        "pr",
+        "checks",
+        url,
+        "--json",
+        "name,state,bucket,link",
+    ]));
+    let json = match result {
+        Ok(json) => json,
+        Err(e) => match e.downcast_ref::<CommandError>() {
+            // `gh pr checks` exits with 1 when checks failed and 8 when
+            // they are pending, and still prints them
+            Some(error) if !error.stdout.trim().is_empty() => error.stdout.clone(),
+            Some(error) if error.stderr.contains("no checks reported") => return Ok(vec![]),
+            _ => return Err(e).with_context(|| format!("Failed to get the checks of {url}")),
+        },
+    };
+    serde_json::from_str(&json).with_context(|| "Failed to parse the output of `gh pr checks`")
+}
+
+fn merge_pull_requests(
+    directories: &[Directory],
+    branch_name: &str,
+    crates: &[Crate],
+    method: MergeMethod,
+    execute: bool,
+    options: RunOptions,
+) -> Result<Vec<RepoReport>> {
+    let ordered: Vec<Directory> = merge_order(directories, crates)?
+        .into_iter()
+        .cloned()
+        .collect();
+    info!(
+        "Merging the `{branch_name}` PRs in this order: {}",
+        ordered
+            .iter()
+            .map(|dir| dir.path.file_name().expect("checked").to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    // Every merge builds on the ones before it, so they run one at a time
+    // and stop at the first failure
+    let options = RunOptions {
+        jobs: 1,
+        fail_fast: true,
+        ..options
+    };
+    let results = for_each_repo(&ordered, options, |dir| {
+        let result = merge_pull_request(dir, branch_name, method, execute);
+        if let Err(e) = &result {
+            error!("{e:?}");
+        }
+        result
+    });
+
+    let mut merged = vec![];
+    let mut already_merged = vec![];
+    let mut ready = vec![];
+    let mut failures = vec![];
+    let (reports, skipped) =
+        collect_reports(&ordered, results, Some(branch_name), |dir, result| {
+            let outcome = match result {
+                Err(e) => {
+                    let report = RepoReport::failed(dir, &e);
+                    failures.push((dir_name(dir), e));
+                    return report;
+                }
+                Ok(outcome) => outcome,
+            };
+            let (MergeOutcome::Merged(url)
+            | MergeOutcome::AlreadyMerged(url)
+            | MergeOutcome::Ready(url)) = &outcome;
+            let report = RepoReport {
+                pr_url: Some(url.clone()),
+                ..RepoReport::new(dir)
+            };
+            match outcome {
+                MergeOutcome::Merged(url) => merged.push((dir_name(dir), url)),
+                MergeOutcome::AlreadyMerged(url) => already_merged.push((dir_name(dir), url)),
+                MergeOutcome::Ready(url) => ready.push((dir_name(dir), url)),
+            }
+            report
+        });
+
+    if !merged.is_empty() {
+        info!("repos whose PR was merged:");
+        for (repo, url) in merged {
+            info!("\t{repo}: {url}");
+        }
+    }
+
+    if !already_merged.is_empty() {
+        info!("repos whose PR was already merged:");
+        for (repo, url) in already_merged {
+            info!("\t{repo}: {url}");
+        }
+    }
+
+    if !ready.is_empty() {
+        info!("repos whose PR is ready to merge, rerun with `--execute` to merge them:");
+        for (repo, url) in ready {
+            info!("\t{repo}: {url}");
         }
     }
 
     if !failures.is_empty() {
-        info!("repos that could not reset:");
-        for repo in failures {
-            info!("\t{repo}");
+        info!("repos whose PR could not be merged:");
+        for (repo, e) in failures {
+            info!("\t{repo}: {e:#}");
         }
     }
-    Ok(())
+    log_skipped(&skipped);
+    Ok(reports)
+}
+
+/// How `merge` merges the PRs, passed to `gh pr merge`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum MergeMethod {
+    /// Create a merge commit
+    Merge,
+    /// Squash the commits into one
+    Squash,
+    /// Rebase the commits onto the base branch
+    Rebase,
+}
+
+impl MergeMethod {
+    fn flag(self) -> &'static str {
+        match self {
+            MergeMethod::Merge => "--merge",
+            MergeMethod::Squash => "--squash",
+            MergeMethod::Rebase => "--rebase",
+        }
+    }
+}
+
+/// What `merge` did with the PR of a repo.
+enum MergeOutcome {
+    Merged(String),
+    AlreadyMerged(String),
+    /// The PR is approved and green, but `--execute` was not given.
+    Ready(String),
+}
+
+/// Sorts `directories` so that each repo comes after the repos that provide
+/// the configured crates it depends on, keeping the config order otherwise.
+fn merge_order<'a>(directories: &'a [Directory], crates: &[Crate]) -> Result<Vec<&'a Directory>> {
+    let crate_names: HashSet<&str> = crates.iter().map(|c| c.name.as_str()).collect();
+    let mut provided = vec![];
+    let mut referenced = vec![];
+    for dir in directories {
+        let content = read_cargo_toml(&dir.path)
+            .with_context(|| format!("Failed to read the manifest of {}", dir.path.display()))?;
+        let packages = workspace_package_names(&dir.path, &content)?;
+        provided.push(
+            packages
+                .into_iter()
+                .filter(|name| crate_names.contains(name.as_str()))
+                .collect::<HashSet<_>>(),
+        );
+        referenced.push(parse_workspace_referenced_crates(&dir.path, &content)?);
+    }
+
+    let mut order = vec![];
+    let mut done = vec![false; directories.len()];
+    while order.len() < directories.len() {
+        let next = (0..directories.len()).find(|&i| {
+            !done[i]
+                && (0..directories.len())
+                    .all(|j| j == i || done[j] || referenced[i].is_disjoint(&provided[j]))
+        });
+        let Some(i) = next else {
+            let remaining = (0..directories.len())
+                .filter(|&i| !done[i])
+                .map(|i| directories[i].path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            bail!("These repos depend on each other in a cycle: {remaining}");
+        };
+        done[i] = true;
+        order.push(&directories[i]);
+    }
+    Ok(order)
+}
+
+/// Merges the PR for `branch_name` once it is approved and all its checks
+/// passed. The directory's `merge_method` takes precedence over `method`.
+fn merge_pull_request(
+    directory: &Directory,
+    branch_name: &str,
+    method: MergeMethod,
+    execute: bool,
+) -> Result<MergeOutcome> {
+    let repo = &directory.path;
+    let Some(pr) = pull_request_status(repo, branch_name)? else {
+        bail!("There is no PR for `{branch_name}`");
+    };
+    let url = pr.url;
+    match pr.state.as_str() {
+        "MERGED" => {
+            info!("{url} is already merged");
+            return Ok(MergeOutcome::AlreadyMerged(url));
+        }
+        "OPEN" => {}
+        state => bail!("{url} is {}", state.to_lowercase()),
+    }
+    if pr.is_draft {
+        bail!("{url} is still a draft");
+    }
+    if pr.review_decision != "APPROVED" {
+        bail!("{url} is not approved");
+    }
+    let unfinished: Vec<String> = pull_request_checks(repo, &url)?
+        .into_iter()
+        .filter(|c| c.bucket != "pass" && c.bucket != "skipping")
+        .map(|c| format!("{} ({})", c.name, c.bucket))
+        .collect();
+    if !unfinished.is_empty() {
+        bail!(
+            "{url} has checks that did not pass: {}",
+            unfinished.join(", ")
+        );
+    }
+
+    if !execute {
+        info!("{url} is ready to merge");
+        return Ok(MergeOutcome::Ready(url));
+    }
+    let method = directory.merge_method.unwrap_or(method);
+    run_command(
+        Cmd::new("gh")
+            .current_dir(repo)
+            .args(["pr", "merge", &url, method.flag()]),
+    )
+    .with_context(|| format!("Failed to merge {url}"))?;
+    info!("Merged {url}");
+    Ok(MergeOutcome::Merged(url))
 }
 
-fn update_deny_toml(updated_crates: &[Crate]) -> Result<()> {
-    let deny_toml_path = Path::new("deny.toml");
+fn update_deny_toml(repo: &Path, updated_crates: &[PatchedCrate]) -> Result<()> {
+    let deny_toml_path = repo.join("deny.toml");
 
     // Check if deny.toml exists
     if !deny_toml_path.exists() {
@@ -631,53 +4810,767 @@ fn update_deny_toml(updated_crates: &[Crate]) -> Result<()> {
 
     // Read the existing deny.toml content
     let deny_toml_content =
-        fs::read_to_string(deny_toml_path).with_context(|| "Failed to read deny.toml")?;
-
-    // Parse the deny.toml file
-    let mut deny_toml: toml::Value =
-        toml::from_str(&deny_toml_content).with_context(|| "Failed to parse deny.toml")?;
-
-    // Extract the list of unique git repo URLs from the updated crates
-    let mut git_repos: HashSet<String> =
-        updated_crates.iter().map(|c| c.repo_url.clone()).collect();
-
-    // Check if the `sources.allow-git` section already exists
-    if let Some(sources) = deny_toml.get_mut("sources") {
-        if let Some(allow_git) = sources.get_mut("allow-git") {
-            if let Some(existing_repos) = allow_git.as_array() {
-                // Add existing repos to the set to deduplicate
-                for repo in existing_repos {
-                    if let Some(repo_str) = repo.as_str() {
-                        git_repos.insert(repo_str.to_string());
-                    }
-                }
-            }
+        fs::read_to_string(&deny_toml_path).with_context(|| "Failed to read deny.toml")?;
+
+    // Write the updated deny.toml back to the file
+    let updated_deny_toml_content = allow_git_sources(&deny_toml_content, updated_crates)?;
+    fs::write(&deny_toml_path, updated_deny_toml_content)
+        .with_context(|| "Failed to write deny.toml")?;
+
+    info!("Updated deny.toml with allowed git repositories.");
+    Ok(())
+}
+
+/// Adds the git sources of the updated crates to `sources.allow-git` of the
+/// given deny.toml.
+///
+/// The existing entries, their order and the comments in the file are kept,
+/// and new sources are added to the end.
+fn allow_git_sources(deny_toml_content: &str, updated_crates: &[PatchedCrate]) -> Result<String> {
+    // Parse deny.toml, keeping comments and ordering, so we can edit it
+    let mut deny_toml: DocumentMut = deny_toml_content
+        .parse()
+        .with_context(|| "Failed to parse deny.toml")?;
+
+    // Create the `sources.allow-git` section if it doesn't exist yet
+    let allow_git = deny_toml
+        .entry("sources")
+        .or_insert(Item::Table(Table::new()))
+        .as_table_like_mut()
+        .with_context(|| "`sources` in deny.toml is not a table")?
+        .entry("allow-git")
+        .or_insert(Item::Value(Array::new().into()))
+        .as_array_mut()
+        .with_context(|| "`sources.allow-git` in deny.toml is not an array")?;
+
+    for patched in updated_crates {
+        let repo_url = &patched.krate.repo_url;
+        if allow_git.iter().any(|repo| repo.as_str() == Some(repo_url)) {
+            continue;
+        }
+        info!("Allowing git source for {patched}");
+        // Put the new entry on its own line when the array is laid out that way
+        let indent = allow_git
+            .iter()
+            .last()
+            .and_then(|last| last.decor().prefix()?.as_str())
+            .and_then(|prefix| prefix.rfind('\n').map(|i| prefix[i..].to_string()));
+        let mut value = toml_edit::Value::from(repo_url.as_str());
+        if let Some(indent) = indent {
+            value.decor_mut().set_prefix(indent);
         }
+        allow_git.push_formatted(value);
     }
 
-    // Create or update the `sources.allow-git` section
-    let allow_git_value = toml::Value::Array(
-        git_repos
-            .into_iter()
-            .map(|repo| toml::Value::String(repo))
-            .collect(),
-    );
+    Ok(deny_toml.to_string())
+}
 
-    deny_toml
-        .as_table_mut()
-        .unwrap()
-        .entry("sources")
-        .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
-        .as_table_mut()
-        .unwrap()
-        .insert("allow-git".to_string(), allow_git_value);
+/// Removes the given git repo URLs from `sources.allow-git` in deny.toml, if
+/// the file exists.
+fn remove_from_deny_toml(repo: &Path, git_repos: &HashSet<String>) -> Result<()> {
+    let deny_toml_path = repo.join("deny.toml");
+
+    // Check if deny.toml exists
+    if !deny_toml_path.exists() {
+        info!("No deny.toml file found. Skipping update.");
+        return Ok(());
+    }
+
+    // Read the existing deny.toml content
+    let deny_toml_content =
+        fs::read_to_string(&deny_toml_path).with_context(|| "Failed to read deny.toml")?;
+
+    let Some(updated_deny_toml_content) = disallow_git_sources(&deny_toml_content, git_repos)?
+    else {
+        return Ok(());
+    };
 
     // Write the updated deny.toml back to the file
-    let updated_deny_toml_content =
-        toml::to_string_pretty(&deny_toml).with_context(|| "Failed to serialize deny.toml")?;
-    fs::write(deny_toml_path, updated_deny_toml_content)
+    fs::write(&deny_toml_path, updated_deny_toml_content)
         .with_context(|| "Failed to write deny.toml")?;
 
-    info!("Updated deny.toml with allowed git repositories.");
+    info!("Removed unused git repositories from deny.toml.");
     Ok(())
 }
+
+/// Removes the given git repo URLs from `sources.allow-git` of the given
+/// deny.toml. Returns `None` when none of them are allowed.
+fn disallow_git_sources(
+    deny_toml_content: &str,
+    git_repos: &HashSet<String>,
+) -> Result<Option<String>> {
+    // Parse deny.toml, keeping comments and ordering, so we can edit it
+    let mut deny_toml: DocumentMut = deny_toml_content
+        .parse()
+        .with_context(|| "Failed to parse deny.toml")?;
+
+    let Some(allow_git) = deny_toml
+        .get_mut("sources")
+        .and_then(|sources| sources.get_mut("allow-git"))
+        .and_then(|allow_git| allow_git.as_array_mut())
+    else {
+        info!("No `sources.allow-git` in deny.toml. Skipping update.");
+        return Ok(None);
+    };
+
+    let mut removed = false;
+    let mut i = 0;
+    while let Some(repo) = allow_git.get(i) {
+        if !repo.as_str().is_some_and(|r| git_repos.contains(r)) {
+            i += 1;
+            continue;
+        }
+        allow_git.remove(i);
+        removed = true;
+        // A comment after the removed entry, on the same line, is stored
+        // before the next entry, or at the end of the array for the last one
+        match allow_git.get_mut(i) {
+            Some(next) => {
+                let prefix = next.decor().prefix().and_then(|p| p.as_str());
+                if let Some(prefix) = prefix.and_then(strip_line_comment) {
+                    next.decor_mut().set_prefix(prefix);
+                }
+            }
+            None => {
+                if let Some(trailing) = allow_git.trailing().as_str().and_then(strip_line_comment) {
+                    allow_git.set_trailing(trailing);
+                }
+            }
+        }
+    }
+    if !removed {
+        return Ok(None);
+    }
+
+    Ok(Some(deny_toml.to_string()))
+}
+
+/// Drops a comment at the start of `whitespace`, up to the end of its line.
+/// Returns `None` when it does not start with a comment.
+fn strip_line_comment(whitespace: &str) -> Option<String> {
+    let (line, rest) = whitespace.split_once('\n')?;
+    line.contains('#').then(|| format!("\n{rest}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn krate(name: &str) -> Crate {
+        Crate {
+            name: name.to_string(),
+            repo_url: format!("https://github.com/n0-computer/{name}.git"),
+            branch: None,
+            rev: None,
+            tag: None,
+        }
+    }
+
+    fn patch(content: &str, crates: &[Crate]) -> String {
+        let repo = Path::new("/nonexistent");
+        let (cargo_toml, _) = add_patches(repo, content, crates, "release", true).unwrap();
+        cargo_toml
+    }
+
+    #[test]
+    fn add_patches_to_existing_table() {
+        let content = r#"[package]
+name = "demo"
+
+[dependencies]
+iroh = "0.30"
+irpc = "0.1"
+
+[patch.crates-io]
+# keep me
+foo = { path = "../foo" }
+
+[profile.release]
+lto = true
+"#;
+        let patched = patch(content, &[krate("iroh"), krate("irpc"), krate("unused")]);
+        assert_eq!(
+            patched,
+            r#"[package]
+name = "demo"
+
+[dependencies]
+iroh = "0.30"
+irpc = "0.1"
+
+[patch.crates-io]
+# keep me
+foo = { path = "../foo" }
+iroh = { git = "https://github.com/n0-computer/iroh.git", branch = "release" }
+irpc = { git = "https://github.com/n0-computer/irpc.git", branch = "release" }
+
+[profile.release]
+lto = true
+"#
+        );
+    }
+
+    #[test]
+    fn add_patches_creates_table() {
+        let content = r#"[package]
+name = "demo"
+
+[dependencies]
+iroh = "0.30"
+"#;
+        let mut iroh = krate("iroh");
+        iroh.tag = Some("v0.31.0".to_string());
+        assert_eq!(
+            patch(content, &[iroh]),
+            r#"[package]
+name = "demo"
+
+[dependencies]
+iroh = "0.30"
+
+[patch.crates-io]
+iroh = { git = "https://github.com/n0-computer/iroh.git", tag = "v0.31.0" }
+"#
+        );
+    }
+
+    #[test]
+    fn add_patches_skips_existing_and_own_packages() {
+        let content = r#"[package]
+name = "iroh"
+
+[dependencies]
+iroh-base = "0.30"
+
+[dev-dependencies]
+iroh = { path = "." }
+
+[patch.crates-io]
+iroh-base = { git = "https://example.com/iroh-base.git" }
+"#;
+        assert_eq!(
+            patch(content, &[krate("iroh"), krate("iroh-base")]),
+            content
+        );
+    }
+
+    #[test]
+    fn referenced_crates_from_every_dependency_table() {
+        let content = r#"[dependencies]
+iroh = "0.30"
+member = { path = "member" }
+
+[build-dependencies]
+irpc = "0.1"
+
+[target.'cfg(unix)'.dev-dependencies]
+iroh-base = "0.30"
+
+[workspace.dependencies]
+iroh-blobs = { version = "0.30", default-features = false }
+"#;
+        assert_eq!(
+            parse_referenced_crates(content).unwrap(),
+            HashSet::from(["iroh", "irpc", "iroh-base", "iroh-blobs"].map(String::from))
+        );
+    }
+
+    #[test]
+    fn workspace_referenced_crates_include_members() {
+        let scratch = scratch_dir("workspace-members");
+        for (member, dependency) in [("a", "iroh"), ("b", "a"), ("skipped", "irpc")] {
+            let path = scratch.join("crates").join(member);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(
+                path.join("Cargo.toml"),
+                format!("[package]\nname = \"{member}\"\n\n[dependencies]\n{dependency} = \"1\"\n"),
+            )
+            .unwrap();
+        }
+        let content = r#"[workspace]
+members = ["crates/*"]
+exclude = ["crates/skipped"]
+"#;
+        let referenced = parse_workspace_referenced_crates(&scratch, content);
+        fs::remove_dir_all(&scratch).unwrap();
+        assert_eq!(referenced.unwrap(), HashSet::from(["iroh".to_string()]));
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(wildcard_match("iroh", "iroh"));
+        assert!(!wildcard_match("iroh", "iroh-base"));
+        assert!(wildcard_match("iroh-*", "iroh-base"));
+        assert!(wildcard_match("iroh*", "iroh"));
+        assert!(!wildcard_match("iroh-*", "iroh"));
+        assert!(wildcard_match("*-base", "iroh-base"));
+        assert!(wildcard_match("iroh-?", "iroh-a"));
+        assert!(!wildcard_match("iroh-?", "iroh-ab"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn renamed_dependencies_use_the_package_name() {
+        let content = r#"[package]
+name = "demo"
+
+[dependencies]
+iroh_net = { package = "iroh", version = "0.30" }
+"#;
+        assert_eq!(
+            parse_referenced_crates(content).unwrap(),
+            HashSet::from(["iroh".to_string()])
+        );
+        assert_eq!(
+            patch(content, &[krate("iroh"), krate("iroh_net")]),
+            format!(
+                "{content}\n[patch.crates-io]\n\
+                 iroh = {{ git = \"https://github.com/n0-computer/iroh.git\", branch = \"release\" }}\n"
+            )
+        );
+    }
+
+    const CARGO_LOCK: &str = r#"version = 3
+
+[[package]]
+name = "demo"
+version = "0.1.0"
+dependencies = ["iroh-blobs", "member"]
+
+[[package]]
+name = "member"
+version = "0.1.0"
+
+[[package]]
+name = "iroh-blobs"
+version = "0.30.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = ["iroh"]
+
+[[package]]
+name = "iroh"
+version = "0.30.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+    #[test]
+    fn locked_crates_skip_local_packages() {
+        assert_eq!(
+            parse_locked_crates(CARGO_LOCK).unwrap(),
+            HashSet::from(["iroh-blobs", "iroh"].map(String::from))
+        );
+    }
+
+    #[test]
+    fn add_patches_marks_transitive_crates() {
+        let scratch = scratch_dir("transitive");
+        fs::create_dir_all(&scratch).unwrap();
+        fs::write(scratch.join("Cargo.lock"), CARGO_LOCK).unwrap();
+        let content = "[package]\nname = \"demo\"\n\n[dependencies]\niroh-blobs = \"0.30\"\n";
+        let crates = [krate("iroh-blobs"), krate("iroh"), krate("member")];
+        let all = add_patches(&scratch, content, &crates, "release", false);
+        let direct = add_patches(&scratch, content, &crates, "release", true);
+        fs::remove_dir_all(&scratch).unwrap();
+
+        let transitive = |patched: &[PatchedCrate]| -> Vec<(String, bool)> {
+            patched
+                .iter()
+                .map(|p| (p.krate.name.clone(), p.transitive))
+                .collect()
+        };
+        assert_eq!(
+            transitive(&all.unwrap().1),
+            [
+                ("iroh-blobs".to_string(), false),
+                ("iroh".to_string(), true)
+            ]
+        );
+        assert_eq!(
+            transitive(&direct.unwrap().1),
+            [("iroh-blobs".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn remove_last_patch_removes_table() {
+        let content = r#"[package]
+name = "demo"
+
+[patch.crates-io]
+iroh = { git = "https://github.com/n0-computer/iroh.git", branch = "release" }
+
+[profile.release]
+lto = true
+"#;
+        let mut cargo_toml: DocumentMut = content.parse().unwrap();
+        let removed = remove_patches(&mut cargo_toml, &HashSet::from(["iroh"]));
+        assert_eq!(removed, HashSet::from(["iroh".to_string()]));
+        assert_eq!(
+            cargo_toml.to_string(),
+            r#"[package]
+name = "demo"
+
+[profile.release]
+lto = true
+"#
+        );
+    }
+
+    #[test]
+    fn remove_patches_keeps_other_entries() {
+        let content = r#"[patch.crates-io]
+foo = { path = "../foo" }
+iroh = { git = "https://github.com/n0-computer/iroh.git", branch = "release" }
+"#;
+        let mut cargo_toml: DocumentMut = content.parse().unwrap();
+        let removed = remove_patches(&mut cargo_toml, &HashSet::from(["iroh", "irpc"]));
+        assert_eq!(removed, HashSet::from(["iroh".to_string()]));
+        assert_eq!(
+            cargo_toml.to_string(),
+            "[patch.crates-io]\nfoo = { path = \"../foo\" }\n"
+        );
+    }
+
+    #[test]
+    fn disallow_git_sources_keeps_comments() {
+        let content = r#"[sources]
+# git sources we trust
+allow-git = [
+  "https://github.com/n0-computer/iroh.git", # iroh
+  "https://github.com/z/zzz.git",
+]
+"#;
+        let iroh = HashSet::from(["https://github.com/n0-computer/iroh.git".to_string()]);
+        assert_eq!(
+            disallow_git_sources(content, &iroh).unwrap().unwrap(),
+            r#"[sources]
+# git sources we trust
+allow-git = [
+  "https://github.com/z/zzz.git",
+]
+"#
+        );
+        let last = "[sources]\nallow-git = [\n  \"https://github.com/z/zzz.git\",\n  \"https://github.com/n0-computer/iroh.git\", # iroh\n]\n";
+        assert_eq!(
+            disallow_git_sources(last, &iroh).unwrap().unwrap(),
+            "[sources]\nallow-git = [\n  \"https://github.com/z/zzz.git\",\n]\n"
+        );
+        let other = HashSet::from(["https://github.com/n0-computer/irpc.git".to_string()]);
+        assert_eq!(disallow_git_sources(content, &other).unwrap(), None);
+        assert_eq!(
+            disallow_git_sources("[bans]\nmultiple-versions = \"warn\"\n", &iroh).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn set_dependency_versions_in_every_table() {
+        let content = r#"[dependencies]
+iroh = "0.30" # the main one
+iroh_net = { package = "iroh-net", version = "0.30", features = ["x"] }
+irpc = "0.1"
+
+[target.'cfg(unix)'.dependencies]
+iroh = { version = "0.30" }
+
+[workspace.dependencies]
+iroh-net = { workspace = true }
+"#;
+        let mut manifest: DocumentMut = content.parse().unwrap();
+        let crates = HashSet::from(["iroh", "iroh-net"].map(String::from));
+        assert!(set_dependency_versions(&mut manifest, &crates, "0.31"));
+        assert_eq!(
+            manifest.to_string(),
+            r#"[dependencies]
+iroh = "0.31" # the main one
+iroh_net = { package = "iroh-net", version = "0.31", features = ["x"] }
+irpc = "0.1"
+
+[target.'cfg(unix)'.dependencies]
+iroh = { version = "0.31" }
+
+[workspace.dependencies]
+iroh-net = { workspace = true }
+"#
+        );
+        let unrelated = HashSet::from(["other".to_string()]);
+        assert!(!set_dependency_versions(&mut manifest, &unrelated, "0.31"));
+    }
+
+    fn load_config_str(test: &str, content: &str) -> Result<Config> {
+        let scratch = scratch_dir(test);
+        fs::create_dir_all(&scratch).unwrap();
+        let path = scratch.join("config.toml");
+        fs::write(&path, content).unwrap();
+        let config = load_config(&path);
+        fs::remove_dir_all(&scratch).unwrap();
+        config
+    }
+
+    #[test]
+    fn directories_are_paths_or_tables() {
+        let config = load_config_str(
+            "config-directories",
+            r#"branch_name = "release"
+directories = [
+    "/work/iroh",
+    { path = "/work/irpc", remote = "upstream", commit = { signoff = true } },
+]
+crates = []
+
+[commit]
+subject = "chore: patch {branch}"
+"#,
+        )
+        .unwrap();
+        let [iroh, irpc] = &config.directories[..] else {
+            panic!("expected two directories");
+        };
+        assert_eq!(iroh.path, Path::new("/work/iroh"));
+        assert_eq!(iroh.remote(), "origin");
+        assert_eq!(irpc.path, Path::new("/work/irpc"));
+        assert_eq!(irpc.remote(), "upstream");
+        assert_eq!(irpc.commit.signoff, Some(true));
+        assert_eq!(
+            irpc.commit.subject.as_deref(),
+            Some("chore: patch {branch}")
+        );
+    }
+
+    #[test]
+    fn directory_typos_are_reported() {
+        let error = |directory: &str| {
+            let content =
+                format!("branch_name = \"release\"\ncrates = []\ndirectories = [{directory}]\n");
+            let Err(e) = load_config_str("config-typos", &content) else {
+                panic!("{directory} should not parse");
+            };
+            format!("{e:#}")
+        };
+        assert!(error(r#"{ path = "/work/iroh", skip_denny = true }"#)
+            .contains("unknown field `skip_denny`"));
+        assert!(
+            error(r#"{ path = "/work/iroh", pull_request = { titel = "x" } }"#)
+                .contains("unknown field `titel`")
+        );
+        assert!(error("3").contains("expected a path or a table with a `path` key"));
+    }
+
+    #[test]
+    fn allow_git_sources_appends_in_place() {
+        let patched: Vec<PatchedCrate> = ["iroh", "irpc"]
+            .map(|name| PatchedCrate {
+                krate: krate(name),
+                git_ref: Some(GitRef::Branch("release".to_string())),
+                transitive: false,
+            })
+            .into();
+        let multi_line = r#"[sources]
+# git sources we trust
+allow-git = [
+  "https://github.com/z/zzz.git", # zzz
+  "https://github.com/n0-computer/iroh.git",
+]
+"#;
+        assert_eq!(
+            allow_git_sources(multi_line, &patched).unwrap(),
+            r#"[sources]
+# git sources we trust
+allow-git = [
+  "https://github.com/z/zzz.git", # zzz
+  "https://github.com/n0-computer/iroh.git",
+  "https://github.com/n0-computer/irpc.git",
+]
+"#
+        );
+        assert_eq!(
+            allow_git_sources("[sources]\nallow-git = [\"https://github.com/z/zzz.git\"]\n", &patched)
+                .unwrap(),
+            "[sources]\nallow-git = [\"https://github.com/z/zzz.git\", \
+             \"https://github.com/n0-computer/iroh.git\", \"https://github.com/n0-computer/irpc.git\"]\n"
+        );
+        assert_eq!(
+            allow_git_sources("[bans]\nmultiple-versions = \"warn\"\n", &patched).unwrap(),
+            "[bans]\nmultiple-versions = \"warn\"\n\n[sources]\nallow-git = [\
+             \"https://github.com/n0-computer/iroh.git\", \"https://github.com/n0-computer/irpc.git\"]\n"
+        );
+    }
+
+    fn scratch_dir(test: &str) -> PathBuf {
+        let scratch =
+            std::env::temp_dir().join(format!("patch-crates-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scratch);
+        scratch
+    }
+
+    fn git(repo: &Path, args: &[&str]) -> String {
+        run_command(Cmd::new("git").current_dir(repo).args(args)).unwrap()
+    }
+
+    fn commit_file(repo: &Path, path: &str, content: &str, message: &str) {
+        fs::write(repo.join(path), content).unwrap();
+        git(repo, &["add", path]);
+        git(repo, &["commit", "--quiet", "--message", message]);
+    }
+
+    fn set_identity(repo: &Path) {
+        git(repo, &["config", "user.name", "Test"]);
+        git(repo, &["config", "user.email", "test@example.com"]);
+    }
+
+    /// Clones a fresh remote into `repo` and adds a `release` branch that
+    /// patches Cargo.toml and changes Cargo.lock, while `main` on the remote
+    /// moves on with a different Cargo.lock.
+    fn diverged_lock_files(scratch: &Path) -> PathBuf {
+        let origin = scratch.join("origin");
+        let repo = scratch.join("repo");
+        fs::create_dir_all(&origin).unwrap();
+        git(&origin, &["init", "--quiet", "--initial-branch", "main"]);
+        set_identity(&origin);
+        commit_file(&origin, "Cargo.toml", "[package]\n", "init");
+        commit_file(&origin, "Cargo.lock", "version = 3\n", "lock");
+        git(
+            scratch,
+            &["clone", "--quiet", origin.to_str().unwrap(), "repo"],
+        );
+        set_identity(&repo);
+        git(&repo, &["checkout", "--quiet", "-b", "release"]);
+        commit_file(
+            &repo,
+            "Cargo.toml",
+            "[package]\n\n[patch.crates-io]\n",
+            "patch",
+        );
+        commit_file(&repo, "Cargo.lock", "version = 3\n# patched\n", "refresh");
+        commit_file(&origin, "Cargo.lock", "version = 3\n# upstream\n", "bump");
+        repo
+    }
+
+    #[test]
+    fn update_branch_takes_upstream_lock_file() {
+        for merge in [false, true] {
+            let scratch = scratch_dir(&format!("update-branch-{merge}"));
+            let repo = diverged_lock_files(&scratch);
+            let result = update_branch(&repo, "origin", "main", "release", merge);
+            let lock_file = fs::read_to_string(repo.join("Cargo.lock")).unwrap();
+            let cargo_toml = fs::read_to_string(repo.join("Cargo.toml")).unwrap();
+            let status = git(&repo, &["status", "--porcelain"]);
+            fs::remove_dir_all(&scratch).unwrap();
+            result.unwrap();
+            assert_eq!(lock_file, "version = 3\n# upstream\n");
+            assert_eq!(cargo_toml, "[package]\n\n[patch.crates-io]\n");
+            assert_eq!(status, "");
+        }
+    }
+
+    #[test]
+    fn checklist_urls() {
+        assert_eq!(
+            checklist_url("- [ ] https://x/pull/1"),
+            Some("https://x/pull/1")
+        );
+        assert_eq!(
+            checklist_url("  - [x] https://x/pull/2 "),
+            Some("https://x/pull/2")
+        );
+        assert_eq!(
+            checklist_url("- [X] https://x/pull/3"),
+            Some("https://x/pull/3")
+        );
+        assert_eq!(checklist_url("- https://x/pull/4"), None);
+        assert_eq!(checklist_url("Notes"), None);
+    }
+
+    #[test]
+    fn tracking_issue_body_starts_a_checklist() {
+        assert_eq!(
+            tracking_issue_body("release", &["https://x/pull/1", "https://x/pull/2"], ""),
+            "Pull requests for the `release` release:\n\n- [ ] https://x/pull/1\n- [ ] https://x/pull/2"
+        );
+    }
+
+    #[test]
+    fn tracking_issue_body_keeps_the_existing_body() {
+        let previous =
+            "Release notes\n\n- [x] https://x/pull/1\n- [ ] https://x/pull/2\n\nPing @team";
+        assert_eq!(
+            tracking_issue_body("release", &["https://x/pull/2", "https://x/pull/3"], previous),
+            "Release notes\n\n- [x] https://x/pull/1\n- [ ] https://x/pull/2\n- [ ] https://x/pull/3\n\nPing @team"
+        );
+        assert_eq!(
+            tracking_issue_body("release", &["https://x/pull/1"], previous),
+            previous
+        );
+        assert_eq!(
+            tracking_issue_body("release", &["https://x/pull/1"], "Release notes"),
+            "Release notes\n- [ ] https://x/pull/1"
+        );
+    }
+
+    /// Creates a repo named `name` in a scratch directory, with a manifest
+    /// for package `name` that depends on `dependencies`.
+    fn scratch_repo(scratch: &Path, name: &str, dependencies: &[&str]) -> Directory {
+        let path = scratch.join(name);
+        fs::create_dir_all(&path).unwrap();
+        let dependencies: String = dependencies
+            .iter()
+            .map(|dep| format!("{dep} = \"1\"\n"))
+            .collect();
+        fs::write(
+            path.join("Cargo.toml"),
+            format!("[package]\nname = \"{name}\"\n\n[dependencies]\n{dependencies}"),
+        )
+        .unwrap();
+        Directory {
+            path,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_order_puts_providers_first() {
+        let scratch = scratch_dir("merge-order");
+        let dirs = [
+            scratch_repo(&scratch, "app", &["iroh", "irpc"]),
+            scratch_repo(&scratch, "other", &[]),
+            scratch_repo(&scratch, "irpc", &["iroh"]),
+            scratch_repo(&scratch, "iroh", &[]),
+        ];
+        let crates = [krate("iroh"), krate("irpc")];
+        let order: Vec<String> = merge_order(&dirs, &crates)
+            .unwrap()
+            .iter()
+            .map(|dir| dir.path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        fs::remove_dir_all(&scratch).unwrap();
+        assert_eq!(order, ["other", "iroh", "irpc", "app"]);
+    }
+
+    #[test]
+    fn merge_order_rejects_cycles() {
+        let scratch = scratch_dir("merge-cycle");
+        let dirs = [
+            scratch_repo(&scratch, "iroh", &["irpc"]),
+            scratch_repo(&scratch, "irpc", &["iroh"]),
+        ];
+        let result = merge_order(&dirs, &[krate("iroh"), krate("irpc")]);
+        fs::remove_dir_all(&scratch).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn merge_order_ignores_crates_that_are_not_configured() {
+        let scratch = scratch_dir("merge-unconfigured");
+        let dirs = [
+            scratch_repo(&scratch, "app", &["serde"]),
+            scratch_repo(&scratch, "serde", &[]),
+        ];
+        let order: Vec<String> = merge_order(&dirs, &[krate("iroh")])
+            .unwrap()
+            .iter()
+            .map(|dir| dir.path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        fs::remove_dir_all(&scratch).unwrap();
+        assert_eq!(order, ["app", "serde"]);
+    }
+}